@@ -1,38 +1,248 @@
-use super::*;
+use {
+    super::*,
+    std::{
+        pin::Pin,
+        task::{ready, Context, Poll},
+    },
+    tokio::io::ReadBuf,
+};
 
 pub struct RouterState {
     pub version: [u64; 3],
     pub address: Ipv6Addr,
+    /// Admin socket `admin_api` is attached to
+    pub uri: String,
     pub admin_api: Endpoint<utils::RWSocket>,
+    /// Cancelled once router address changes, sessions set up with the previous one are closed
+    pub address_changed: CancellationToken,
 }
 
-#[instrument(parent = None, name = "Admin API", skip_all)]
-pub async fn connect(config: Config) -> Result<RouterState, ()> {
-    use std::io::{Error, ErrorKind};
-    let error = |t| Error::new(ErrorKind::InvalidInput, t);
+/// Largest admin API response accepted, so a misbehaving socket can't exhaust memory
+const MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Admin socket passing data to the reader only up to the end of a complete JSON value.
+/// `Endpoint` tells responses apart heuristically, so it could parse one delivered in chunks
+/// before the rest has arrived
+pub struct AdminSocket<S> {
+    inner: S,
+    /// Bytes received from the socket, not yet passed to the reader
+    pending: Vec<u8>,
+    /// Length of `pending` prefix holding a complete value or whitespace
+    complete: usize,
+    /// Length of `pending` prefix already scanned
+    scanned: usize,
+    /// Nesting depth, string and escape state at the end of the scanned prefix
+    depth: usize,
+    string: bool,
+    escaped: bool,
+}
+
+impl<S> AdminSocket<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+            complete: 0,
+            scanned: 0,
+            depth: 0,
+            string: false,
+            escaped: false,
+        }
+    }
+
+    /// Find the end of the first value in `pending` and whitespace following it,
+    /// resuming where the last scan stopped
+    fn scan(&mut self) -> IoResult<()> {
+        let mut i = self.scanned;
+        while let Some(&byte) = self.pending.get(i) {
+            if self.depth == 0 {
+                if byte.is_ascii_whitespace() {
+                    self.complete = i + 1;
+                } else if self.complete > 0 {
+                    break;
+                } else if byte == b'{' || byte == b'[' {
+                    self.depth = 1;
+                } else {
+                    return Err(IoError::new(
+                        IoErrorKind::InvalidData,
+                        "Response is not a JSON object",
+                    ));
+                }
+            } else if self.string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.string = false,
+                    _ => {}
+                }
+            } else {
+                match byte {
+                    b'"' => self.string = true,
+                    b'{' | b'[' => self.depth += 1,
+                    b'}' | b']' => {
+                        self.depth -= 1;
+                        if self.depth == 0 {
+                            self.complete = i + 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            i += 1;
+        }
+        self.scanned = i;
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for AdminSocket<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let this = &mut *self;
+        loop {
+            if this.complete > 0 {
+                let len = this.complete.min(buf.remaining());
+                buf.put_slice(&this.pending[..len]);
+                this.pending.drain(..len);
+                this.complete -= len;
+                this.scanned -= len;
+                return Poll::Ready(Ok(()));
+            }
 
+            this.scan()?;
+            if this.complete > 0 {
+                continue;
+            }
+            if this.pending.len() > MAX_RESPONSE_SIZE {
+                return Poll::Ready(Err(IoError::new(
+                    IoErrorKind::InvalidData,
+                    format!("Response exceeds {MAX_RESPONSE_SIZE} bytes"),
+                )));
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return Poll::Ready(match this.pending.is_empty() {
+                    true => Ok(()),
+                    false => Err(IoErrorKind::UnexpectedEof.into()),
+                });
+            }
+            this.pending.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for AdminSocket<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IoResult<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Delay before racing connection to the next resolved address, see RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to the first responding address of dual-stack hostname, interleaving address families
+/// starting with IPv6, so unreachable family doesn't stall the connection
+async fn connect_tcp(
+    config: &config::ConfigInner,
+    dns_cache: &utils::DnsCache,
+    address: &str,
+) -> IoResult<TcpStream> {
+    let (ipv6, ipv4): (Vec<_>, Vec<_>) = utils::resolve(config, dns_cache, address)
+        .await
+        .map_err(|_| IoError::new(IoErrorKind::NotFound, "Failed to lookup address"))?
+        .into_iter()
+        .partition(|a| a.is_ipv6());
+    let addresses = ipv6.into_iter().interleave(ipv4);
+
+    let mut attempts = JoinSet::new();
+    for (i, address) in addresses.enumerate() {
+        attempts.spawn(async move {
+            sleep(CONNECTION_ATTEMPT_DELAY * i as u32).await;
+            TcpStream::connect(address).await
+        });
+    }
+
+    let mut last_err = None;
+    while let Some(attempt) = attempts.join_next().await {
+        match attempt {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => last_err = Some(err),
+            Err(err) => last_err = Some(err.into()),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        IoError::new(
+            IoErrorKind::NotFound,
+            format!("No addresses resolved for {address}"),
+        )
+    }))
+}
+
+/// Open connection to the admin socket at `uri`
+async fn connect_socket(
+    config: &config::ConfigInner,
+    dns_cache: &utils::DnsCache,
+    uri: &str,
+) -> IoResult<utils::RWSocket> {
+    let error = |t| IoError::new(IoErrorKind::InvalidInput, t);
+    let Some((protocol, address)) = uri.split_once("://") else {
+        return Err(error(format!(
+            "Can't parse yggdrasil admin socket address {uri}"
+        )));
+    };
+    match protocol {
+        #[cfg(unix)]
+        "unix" => tokio::net::UnixStream::connect(address)
+            .await
+            .map(|s| -> utils::RWSocket { Box::new(s) }),
+        #[cfg(not(unix))]
+        "unix" => Err(error(format!(
+            "Unix socket is not supported on this platform"
+        ))),
+        "tcp" => connect_tcp(config, dns_cache, address).await.map(|s| -> utils::RWSocket {
+            // Yggdrasil admin api has no authentication
+            if let Ok(peer) = s.peer_addr() {
+                if !peer.ip().is_loopback() {
+                    warn!("Admin socket {uri} is not on loopback, commands are sent unauthenticated and unencrypted");
+                    warn!("Help: Prefer unix socket or loopback address for `yggdrasil_admin_listen`");
+                }
+            }
+            Box::new(s)
+        }),
+        _ => Err(error(format!("Invalid protocol '{protocol}'"))),
+    }
+}
+
+#[instrument(parent = None, name = "Admin API", skip_all)]
+pub async fn connect(config: Config, dns_cache: &utils::DnsCache) -> Result<RouterState, ()> {
     let mut errs: Vec<(_, _)> = Vec::new();
 
     for uri in &config.yggdrasil_admin_listen {
-        if let Some((protocol, address)) = uri.split_once("://") {
-            let socket = match protocol {
-                #[cfg(unix)]
-                "unix" => tokio::net::UnixStream::connect(address)
-                    .await
-                    .map(|s| -> utils::RWSocket { Box::new(s) }),
-                #[cfg(not(unix))]
-                "unix" => Err(error(format!(
-                    "Unix socket is not supported on this platform"
-                ))),
-                "tcp" => TcpStream::connect(address)
-                    .await
-                    .map(|s| -> utils::RWSocket { Box::new(s) }),
-                _ => Err(error(format!("Invalid protocol '{protocol}'"))),
-            };
+        if uri.contains("://") {
+            let socket = connect_socket(&config, dns_cache, uri).await;
             match socket {
                 Err(err) => errs.push((uri, err)),
                 Ok(socket) => {
                     info!("Connected to {uri}");
+                    let socket: utils::RWSocket = Box::new(AdminSocket::new(socket));
                     let mut endpoint = Endpoint::attach(socket).await;
 
                     // Check router version
@@ -69,14 +279,11 @@ pub async fn connect(config: Config) -> Result<RouterState, ()> {
                     }
 
                     // If router version is lower then v0.5.0 and quic protocol is specified
-                    if config
-                        .yggdrasil_protocols
-                        .iter()
-                        .any(|p| *p == PeeringProtocol::Quic)
+                    if config.yggdrasil_protocols.contains(&PeeringProtocol::Quic)
+                        && version[0] == 0
+                        && version[1] < 5
                     {
-                        if version[0] == 0 && version[1] < 5 {
-                            warn!("Transport protocol Quic is not supported by the router of version {build_version} at {uri}");
-                        }
+                        warn!("Transport protocol Quic is not supported by the router of version {build_version} at {uri}");
                     }
 
                     // If any client-server peering protocol doesn't have `listen` peer listed
@@ -85,25 +292,37 @@ pub async fn connect(config: Config) -> Result<RouterState, ()> {
                         .iter()
                         .filter(|p| **p != PeeringProtocol::Tcp)
                     {
-                        if !config
-                            .yggdrasil_listen
-                            .iter()
-                            .filter_map(|a| {
-                                a.split("://")
-                                    .next()
-                                    .and_then(|p| PeeringProtocol::from_str(p).ok())
-                            })
-                            .any(|p| p == *protocol)
-                        {
+                        if !protocol.is_listened_by_router(&config) {
                             warn!("Transport protocol {protocol:?} is client-server only and it is unable to create peering");
                             warn!("If both peering nodes have no appropriate `yggdrasil_listen` URI set in the config");
                         }
                     }
 
+                    info!("Router version: {build_version}, address: {}", info.address);
+                    info!(
+                        "Peering protocols: {}",
+                        config
+                            .yggdrasil_protocols
+                            .iter()
+                            .map(|p| {
+                                match (
+                                    p.is_supported_by_router(version),
+                                    p.is_listened_by_router(&config),
+                                ) {
+                                    (false, _) => format!("{} (unsupported)", p.id()),
+                                    (true, true) => format!("{} (listened)", p.id()),
+                                    (true, false) => p.id().to_string(),
+                                }
+                            })
+                            .join(", ")
+                    );
+
                     return Ok(RouterState {
                         version,
                         address: info.address,
+                        uri: uri.clone(),
                         admin_api: endpoint,
+                        address_changed: CancellationToken::new(),
                     });
                 }
             }
@@ -118,6 +337,31 @@ pub async fn connect(config: Config) -> Result<RouterState, ()> {
     Err(())
 }
 
+/// Failure of the admin api poll
+enum QueryError {
+    /// Connection failed, it's unlikely to recover
+    Io,
+    /// Router failed to handle the command
+    Api,
+}
+
+/// Attach new endpoint to the admin socket in use, replacing the failed connection
+async fn reattach(config: &config::ConfigInner, state: &State) -> Result<(), ()> {
+    let router = &mut *state.router.write().await;
+    let uri = router.uri.clone();
+    let socket = connect_socket(config, &state.dns_cache, &uri)
+        .await
+        .map_err(map_warn!("Failed to reconnect to {uri}"))?;
+    let socket: utils::RWSocket = Box::new(AdminSocket::new(socket));
+    router.admin_api = Endpoint::attach(socket).await;
+    info!("Reconnected to {uri}");
+    Ok(())
+}
+
+/// Fraction `yggdrasilctl_query_delay` is randomly varied by, so jumpers sharing a router
+/// or restarted together don't poll it in sync
+const QUERY_DELAY_JITTER: f64 = 0.1;
+
 #[instrument(parent = None, name = "Admin API watcher", skip_all)]
 pub async fn monitor(
     config: Config,
@@ -126,13 +370,21 @@ pub async fn monitor(
     watch_peers: watch::Sender<Vec<yggdrasilctl::PeerEntry>>,
 ) -> Result<(), ()> {
     let cancellation = state.cancellation.clone();
+    let mut failures = 0u64;
 
     loop {
-        {
-            let io_err = map_error!("Failed to query admin api");
-            let api_err = map_error!("Admin api returned error");
+        let result = async {
+            let io_err = |err| {
+                error!("Failed to query admin api: {err}");
+                QueryError::Io
+            };
+            let api_err = |err| {
+                error!("Admin api returned error: {err}");
+                QueryError::Api
+            };
 
-            let endpoint = &mut state.router.write().await.admin_api;
+            let router = &mut *state.router.write().await;
+            let endpoint = &mut router.admin_api;
 
             watch_sessions
                 .send(
@@ -143,19 +395,127 @@ pub async fn monitor(
                         .map_err(api_err)?,
                 )
                 .unwrap();
-            watch_peers
-                .send(
-                    endpoint
-                        .get_peers()
-                        .await
-                        .map_err(io_err)?
-                        .map_err(api_err)?,
-                )
-                .unwrap();
+            let peers = endpoint
+                .get_peers()
+                .await
+                .map_err(io_err)?
+                .map_err(api_err)?;
+
+            // Router address may have changed, e.g. after key rotation, query it every poll
+            let address = endpoint
+                .get_self()
+                .await
+                .map_err(io_err)?
+                .map_err(api_err)?
+                .address;
+            if address != router.address {
+                warn!("Router address changed: {} -> {address}", router.address);
+                warn!("Closing sessions and bridges set up with the previous address");
+                router.address = address;
+                router.address_changed.cancel();
+                router.address_changed = CancellationToken::new();
+            }
+
+            watch_peers.send(peers).unwrap();
+            Result::<(), QueryError>::Ok(())
+        }
+        .await;
+
+        // Retry the same admin socket, query errors may be transient.
+        // Connection is opened anew after I/O errors, e.g. if the router has restarted
+        match result {
+            Ok(()) => failures = 0,
+            Err(err) if failures < config.yggdrasilctl_query_retry_count => {
+                failures += 1;
+                warn!(
+                    "Retrying admin api query ({failures}/{})",
+                    config.yggdrasilctl_query_retry_count
+                );
+                if let QueryError::Io = err {
+                    reattach(&config, &state).await.ok();
+                }
+            }
+            Err(_) => return Err(()),
         }
+
+        let jitter = rand::Rng::gen_range(
+            &mut rand::thread_rng(),
+            1.0 - QUERY_DELAY_JITTER..=1.0 + QUERY_DELAY_JITTER,
+        );
         select! {
-            _ = sleep(config.yggdrasilctl_query_delay) => {},
+            _ = sleep(config.yggdrasilctl_query_delay.mul_f64(jitter)) => {},
             _ = cancellation.cancelled() => return Ok(()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {super::*, tokio::io::AsyncReadExt};
+
+    #[tokio::test]
+    async fn chunked_response() {
+        let peers: Vec<_> = (0..2000)
+            .map(|i| {
+                serde_json::json!({
+                    "key": format!("{i:064x}"),
+                    "port": i,
+                    "remote": format!("tcp://[200::{i:x}]:4701"),
+                    "up": true,
+                    "inbound": false,
+                })
+            })
+            .collect();
+        let response = serde_json::to_vec_pretty(&serde_json::json!({
+            "status": "success",
+            "response": { "peers": peers },
+        }))
+        .unwrap();
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        spawn(async move {
+            let mut request = [0u8; 1024];
+            for _ in 0..2 {
+                let _ = server.read(&mut request).await;
+                // Break response at every closing brace, the way the router never would
+                for chunk in response.split_inclusive(|b| *b == b'}') {
+                    server.write_all(chunk).await.unwrap();
+                    server.flush().await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+                server.write_all(b"\n").await.unwrap();
+            }
+        });
+
+        let mut endpoint = Endpoint::attach_version(
+            AdminSocket::new(client),
+            yggdrasilctl::RouterVersion::v0_5_0__,
+        );
+        for _ in 0..2 {
+            let peers = endpoint.get_peers().await.unwrap().unwrap();
+            assert_eq!(peers.len(), 2000);
+            assert_eq!(peers[1999].port, 1999);
+        }
+    }
+
+    #[tokio::test]
+    async fn separate_values() {
+        let mut socket = AdminSocket::new(&b"{\"a\": \"}\\\"\"}\n\n[{}]"[..]);
+        let mut buf = Vec::new();
+        let mut read = [0u8; 64];
+        loop {
+            let len = socket.read(&mut read).await.unwrap();
+            if len == 0 {
+                break;
+            }
+            buf.push(String::from_utf8(read[..len].to_vec()).unwrap());
+        }
+        assert_eq!(buf, ["{\"a\": \"}\\\"\"}\n\n", "[{}]"]);
+
+        let mut socket = AdminSocket::new(&b"{\"a\": 1"[..]);
+        assert!(socket.read(&mut read).await.is_err());
+
+        let mut socket = AdminSocket::new(&b"null"[..]);
+        assert!(socket.read(&mut read).await.is_err());
+    }
+}
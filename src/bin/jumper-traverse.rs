@@ -0,0 +1,118 @@
+use {
+    tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt},
+    yggdrasil_jumper::*,
+};
+
+#[derive(Debug, clap::Parser)]
+#[command(name = "jumper-traverse", version)]
+pub struct CliArgs {
+    #[arg(help = "Remote external address, e.g. 203.0.113.1:4400")]
+    pub remote: SocketAddr,
+    #[arg(
+        short,
+        long,
+        help = "Local port to traverse from",
+        default_value = "4400"
+    )]
+    pub port: u16,
+    #[arg(long, help = "Read traversal parameters from specified config file")]
+    pub config: Option<PathBuf>,
+    #[arg(long, help = "Set log verbosity level", default_value = "INFO")]
+    pub loglevel: LevelFilter,
+    #[arg(
+        long,
+        help = "Set per-module log levels, e.g. \"yggdrasil_jumper::network=debug,info\". Overrides --loglevel"
+    )]
+    pub log_filter: Option<tracing_subscriber::filter::Targets>,
+    #[arg(long = "no-color", help = "Whether to disable auto coloring", action = clap::ArgAction::SetFalse)]
+    pub use_color: bool,
+    #[arg(short = 't', long, help = "Use TCP simultaneous open")]
+    #[arg(required_unless_present = "udp", conflicts_with = "udp")]
+    pub tcp: bool,
+    #[arg(short = 'u', long, help = "Use UDP hello exchange")]
+    #[arg(required_unless_present = "tcp", conflicts_with = "tcp")]
+    pub udp: bool,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let (mut cancellation_root, cancellation) = utils::cancellation();
+    let err = start(cancellation).await;
+    cancellation_root.cancel().await;
+    err.map_err(|_| std::process::exit(1)).ok();
+}
+
+async fn start(cancellation: CancellationUnit) -> Result<(), ()> {
+    // Parse CLI arguments
+    let cli_args: CliArgs = clap::Parser::try_parse().map_err(|e| e.exit())?;
+
+    // Init logger
+    tracing_subscriber::fmt()
+        .with_target(false)
+        .with_file(false)
+        .with_thread_names(false)
+        .with_ansi(
+            cli_args.use_color
+                && std::io::IsTerminal::is_terminal(&std::io::stdout())
+                && std::env::var_os("TERM").is_some(),
+        )
+        .with_max_level(match cli_args.log_filter {
+            Some(_) => LevelFilter::TRACE,
+            None => cli_args.loglevel,
+        })
+        .without_time()
+        .log_internal_errors(false)
+        .with_writer(std::io::stderr)
+        .finish()
+        .with(cli_args.log_filter.clone())
+        .init();
+
+    // Load config
+    let config = match cli_args.config {
+        Some(ref path) => config::ConfigInner::read(path.as_path()).map_err(|_| ())?,
+        None => config::ConfigInner::default(),
+    }
+    .check()
+    .map_err(|_| ())?;
+
+    let local = match cli_args.remote {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, cli_args.port)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, cli_args.port)),
+    };
+
+    info!("Traversing {local} -> {}", cli_args.remote);
+
+    let traverse = async {
+        if cli_args.tcp {
+            network::traverse_tcp(&config, &cancellation, local, cli_args.remote, None)
+                .await
+                .and_then(|s| Ok((s.local_addr()?, s.peer_addr()?)))
+        } else {
+            // Without a side channel, consider traversal done once the remote hello is received
+            let traversed = oneshot::channel();
+            network::traverse_udp(
+                &config,
+                &cancellation,
+                local,
+                cli_args.remote,
+                Some(traversed.0),
+                Some(traversed.1),
+            )
+            .await
+            .and_then(|s| Ok((s.local_addr()?, s.peer_addr()?)))
+        }
+    };
+
+    select! {
+        res = traverse => {
+            let (local, remote) = res.map_err(map_error!("NAT traversal failed"))?;
+            info!("NAT traversal succeeded");
+            println!("{local} -> {remote}");
+            Ok(())
+        },
+        _ = tokio::signal::ctrl_c() => {
+            warn!("Stop signal received");
+            Err(())
+        },
+    }
+}
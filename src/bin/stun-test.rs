@@ -1,9 +1,12 @@
-use yggdrasil_jumper::*;
+use {
+    tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt},
+    yggdrasil_jumper::*,
+};
 
 #[derive(Debug, clap::Parser)]
 #[command(name = "stun-test", version)]
 pub struct CliArgs {
-    #[arg(required_unless_present_any = [ "config", "default" ])]
+    #[arg(required_unless_present_any = [ "config", "default", "serve" ])]
     pub servers: Vec<String>,
     #[arg(long, help = "Read servers from specified config file")]
     pub config: Option<PathBuf>,
@@ -11,6 +14,11 @@ pub struct CliArgs {
     pub default: bool,
     #[arg(long, help = "Set log verbosity level", default_value = "INFO")]
     pub loglevel: LevelFilter,
+    #[arg(
+        long,
+        help = "Set per-module log levels, e.g. \"yggdrasil_jumper::network=debug,info\". Overrides --loglevel"
+    )]
+    pub log_filter: Option<tracing_subscriber::filter::Targets>,
     #[arg(long = "no-color", help = "Whether to disable auto coloring", action = clap::ArgAction::SetFalse)]
     pub use_color: bool,
     #[arg(short = '6', long, help = "Use only IPv6", conflicts_with = "ipv4")]
@@ -19,15 +27,32 @@ pub struct CliArgs {
     #[arg(conflicts_with = "ipv6", default_value = "true")]
     pub ipv4: bool,
     #[arg(short = 't', long, help = "Use only TCP")]
-    #[arg(required_unless_present = "udp", conflicts_with = "udp")]
+    #[arg(required_unless_present_any = [ "udp", "serve", "probe_mtu" ], conflicts_with = "udp")]
     pub tcp: bool,
     #[arg(short = 'u', long, help = "Use only UDP")]
-    #[arg(required_unless_present = "tcp", conflicts_with = "tcp")]
+    #[arg(required_unless_present_any = [ "tcp", "serve", "probe_mtu" ], conflicts_with = "tcp")]
     pub udp: bool,
     #[arg(long, help = "Print server for every resolved address")]
     pub print_servers: bool,
     #[arg(long = "no-check", help = "Skip all address consistency checks", action = clap::ArgAction::SetFalse)]
     pub check: bool,
+    #[arg(
+        long,
+        help = "Serve STUN binding requests over TCP and UDP at specified address"
+    )]
+    pub serve: Option<SocketAddr>,
+    #[arg(
+        long,
+        help = "Resolve up to N servers concurrently and print summary table",
+        value_name = "N"
+    )]
+    pub parallel: Option<usize>,
+    #[arg(
+        long,
+        help = "Print largest UDP payload size in bytes answered by every server",
+        conflicts_with_all = [ "tcp", "parallel" ]
+    )]
+    pub probe_mtu: bool,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -49,12 +74,37 @@ async fn start() -> Result<(), ()> {
                 && std::io::IsTerminal::is_terminal(&std::io::stdout())
                 && std::env::var_os("TERM").is_some(),
         )
-        .with_max_level(cli_args.loglevel)
+        .with_max_level(match cli_args.log_filter {
+            Some(_) => LevelFilter::TRACE,
+            None => cli_args.loglevel,
+        })
         .without_time()
         .log_internal_errors(false)
         .with_writer(std::io::stderr)
+        .finish()
+        .with(cli_args.log_filter.clone())
         .init();
 
+    // Run server
+    if let Some(address) = cli_args.serve {
+        let socket = UdpSocket::bind(address)
+            .await
+            .map_err(map_error!("Failed to bind UDP socket to {address}"))?;
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(map_error!("Failed to bind TCP listener to {address}"))?;
+        info!("Serving at {address}");
+
+        return select! {
+            err = stun::serve_udp(socket) => err,
+            err = stun::serve_tcp(listener) => err,
+            _ = tokio::signal::ctrl_c() => {
+                info!("Stop signal received");
+                Ok(())
+            },
+        };
+    }
+
     // Allocate socket port
     if cli_args.ipv6 {
         cli_args.ipv4 = false;
@@ -67,17 +117,21 @@ async fn start() -> Result<(), ()> {
 
     let local_address = SocketAddr::from((ip_domain, 0));
     let local_address = if cli_args.tcp {
-        utils::create_tcp_socket(local_address)?.local_addr()
+        utils::create_tcp_socket(local_address, false)?.local_addr()
     } else {
-        utils::create_udp_socket(local_address)?.local_addr()
+        utils::create_udp_socket(local_address, false)?.local_addr()
     }
     .map_err(map_error!("Failed to retrieve local socket address"))?;
 
     // Load config
-    let config = Arc::new(match cli_args.config {
-        Some(ref path) => config::ConfigInner::read(path.as_path())?,
-        None => config::ConfigInner::default(),
-    });
+    let config = Arc::new(
+        match cli_args.config {
+            Some(ref path) => config::ConfigInner::read(path.as_path()).map_err(|_| ())?,
+            None => config::ConfigInner::default(),
+        }
+        .check()
+        .map_err(|_| ())?,
+    );
 
     // Load server list
     if cli_args.config.is_some() {
@@ -92,17 +146,36 @@ async fn start() -> Result<(), ()> {
             .append(&mut config::ConfigInner::default().stun_servers);
     }
 
+    let protocol = if cli_args.tcp {
+        NetworkProtocol::Tcp
+    } else {
+        NetworkProtocol::Udp
+    };
+
+    if cli_args.probe_mtu {
+        for server in cli_args.servers {
+            let _span = error_span!("While probing ", server = %server);
+            let _span = _span.enter();
+
+            let size = stun::probe_mtu(config.clone(), local_address, &server).await?;
+            if cli_args.print_servers {
+                print!("{server} ");
+            }
+            println!("{size}");
+        }
+        return Ok(());
+    }
+
+    if let Some(parallel) = cli_args.parallel {
+        return resolve_parallel(config, protocol, local_address, cli_args, parallel.max(1)).await;
+    }
+
     let mut last_address = None;
     for server in cli_args.servers {
         let _span = error_span!("While resolving ", server = %server);
         let _span = _span.enter();
 
         // Connect to server
-        let protocol = if cli_args.tcp {
-            NetworkProtocol::Tcp
-        } else {
-            NetworkProtocol::Udp
-        };
         let external_address = stun::lookup(config.clone(), protocol, local_address, &server)
             .await?
             .external;
@@ -135,3 +208,100 @@ async fn start() -> Result<(), ()> {
     }
     Ok(())
 }
+
+async fn resolve_parallel(
+    config: Config,
+    protocol: NetworkProtocol,
+    local_address: SocketAddr,
+    cli_args: CliArgs,
+    parallel: usize,
+) -> Result<(), ()> {
+    let mut servers = cli_args.servers.into_iter().enumerate();
+    let mut tasks = JoinSet::new();
+    let mut results = Vec::new();
+
+    loop {
+        // Keep up to `parallel` lookups running
+        while tasks.len() < parallel {
+            let Some((index, server)) = servers.next() else {
+                break;
+            };
+            let config = config.clone();
+            let span = error_span!("While resolving ", server = %server);
+            tasks.spawn(
+                async move {
+                    let started = Instant::now();
+                    let result = stun::lookup(config, protocol, local_address, &server)
+                        .await
+                        .map(|address| address.external);
+                    (index, server, result, started.elapsed())
+                }
+                .instrument(span),
+            );
+        }
+
+        match tasks.join_next().await {
+            Some(result) => results.push(result.map_err(map_error!("Failed to join task"))?),
+            None => break,
+        }
+    }
+    results.sort_by_key(|(index, ..)| *index);
+
+    // Print summary table
+    let rows: Vec<_> = results
+        .iter()
+        .map(|(_, server, result, latency)| match result {
+            Ok(address) => (server, address.to_string(), latency, "ok"),
+            Err(_) => (server, "-".to_string(), latency, "fail"),
+        })
+        .collect();
+    let server_width = rows
+        .iter()
+        .map(|row| row.0.len())
+        .fold("SERVER".len(), usize::max);
+    let address_width = rows
+        .iter()
+        .map(|row| row.1.len())
+        .fold("ADDRESS".len(), usize::max);
+
+    println!(
+        "{:server_width$}  {:address_width$}  {:>9}  STATUS",
+        "SERVER", "ADDRESS", "LATENCY"
+    );
+    for (server, address, latency, status) in &rows {
+        println!(
+            "{server:server_width$}  {address:address_width$}  {:>7}ms  {status}",
+            latency.as_millis()
+        );
+    }
+
+    if results.iter().any(|(_, _, result, _)| result.is_err()) {
+        error!("Some servers failed to resolve");
+        return Err(());
+    }
+
+    // Check address consistency
+    if cli_args.check {
+        let addresses: Vec<_> = results
+            .iter()
+            .filter_map(|(_, _, result, _)| result.as_ref().ok())
+            .unique()
+            .collect();
+        if addresses
+            .iter()
+            .any(|address| address.is_ipv4() != local_address.is_ipv4())
+        {
+            error!("Resolved address has wrong range");
+            return Err(());
+        }
+        if addresses.len() > 1 {
+            error!(
+                "Resolved addresses don't match: {}",
+                addresses.iter().join(", ")
+            );
+            return Err(());
+        }
+    }
+
+    Ok(())
+}
@@ -1,4 +1,7 @@
-use yggdrasil_jumper::*;
+use {
+    tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt},
+    yggdrasil_jumper::*,
+};
 
 #[derive(Debug, clap::Parser)]
 #[command(version)]
@@ -7,51 +10,136 @@ pub struct CliArgs {
     pub config: Option<PathBuf>,
     #[arg(long, help = "Print default config and exit")]
     pub print_default: bool,
+    #[arg(
+        long,
+        help = "Print effective config, including values not listed by default, and exit"
+    )]
+    pub print_config: bool,
     #[arg(long, help = "Validate config and exit")]
     pub validate: bool,
     #[arg(long, help = "Set log verbosity level", default_value = "INFO")]
     pub loglevel: LevelFilter,
+    #[arg(
+        long,
+        help = "Set per-module log levels, e.g. \"yggdrasil_jumper::network=debug,info\". Overrides --loglevel"
+    )]
+    pub log_filter: Option<tracing_subscriber::filter::Targets>,
     #[arg(long = "no-color", help = "Whether to disable auto coloring", action = clap::ArgAction::SetFalse)]
     pub use_color: bool,
+    #[arg(long, help = "Use only specified STUN server")]
+    pub stun_server: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        help = "Log why session with specified yggdrasil address is or isn't bridged"
+    )]
+    pub explain: Vec<Ipv6Addr>,
+    #[arg(
+        long,
+        help = "Set log output format",
+        value_enum,
+        default_value = "text"
+    )]
+    pub log_format: LogFormat,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Exit without awaiting cleanup after specified time on shutdown",
+        default_value = "10"
+    )]
+    pub shutdown_timeout: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
+    // Read CLI arguments
+    let cli_args: CliArgs = clap::Parser::try_parse().unwrap_or_else(|err| err.exit());
+    let shutdown_timeout =
+        Duration::try_from_secs_f64(cli_args.shutdown_timeout).unwrap_or(Duration::MAX);
+
     let (mut cancellation_root, cancellation) = utils::cancellation();
-    let err = start(cancellation).await;
-    cancellation_root.cancel().await;
+    let registered_peers = bridge::RegisteredPeers::default();
+    let err = start(cli_args, cancellation, registered_peers.clone()).await;
+
+    // Peerings left in the router are removed on the next start
+    if timeout(shutdown_timeout, cancellation_root.cancel())
+        .await
+        .is_err()
+    {
+        warn!("Shutdown timed out");
+        for uri in registered_peers.lock().unwrap().iter() {
+            warn!("Peer {uri} may be left in the router");
+        }
+    }
     err.map_err(|_| std::process::exit(1)).ok();
 }
 
-pub async fn start(cancellation: utils::CancellationUnit) -> Result<(), ()> {
-    // Read CLI arguments
-    let cli_args: CliArgs = clap::Parser::try_parse().unwrap_or_else(|err| err.exit());
-
+pub async fn start(
+    cli_args: CliArgs,
+    cancellation: utils::CancellationUnit,
+    registered_peers: bridge::RegisteredPeers,
+) -> Result<(), ()> {
     if cli_args.print_default {
         print!("{}", config::ConfigInner::default_str());
         return Ok(());
     }
 
     // Init logger
-    tracing_subscriber::fmt()
+    let logger = tracing_subscriber::fmt()
         .with_target(false)
         .with_file(false)
         .with_thread_names(false)
         .with_ansi(
             cli_args.use_color
+                && cli_args.log_format == LogFormat::Text
                 && std::io::IsTerminal::is_terminal(&std::io::stdout())
                 && std::env::var_os("TERM").is_some(),
         )
-        .with_max_level(cli_args.loglevel)
+        .with_max_level(match cli_args.log_filter {
+            Some(_) => LevelFilter::TRACE,
+            None => cli_args.loglevel,
+        })
         .without_time()
-        .log_internal_errors(false)
-        .init();
+        .log_internal_errors(false);
+    match cli_args.log_format {
+        LogFormat::Text => logger.finish().with(cli_args.log_filter.clone()).init(),
+        LogFormat::Json => logger
+            .fmt_fields(utils::JsonFields)
+            .event_format(utils::JsonFormat)
+            .finish()
+            .with(cli_args.log_filter.clone())
+            .init(),
+    }
 
     // Read config file
-    let config = Arc::new(match cli_args.config {
-        Some(ref path) => config::ConfigInner::read(path)?,
+    let mut config = match cli_args.config {
+        Some(ref path) => config::ConfigInner::read(path).map_err(|_| ())?,
         None => config::ConfigInner::default(),
-    });
+    }
+    .apply_env()
+    .map_err(|_| ())?;
+
+    // Apply CLI overrides
+    if let Some(server) = cli_args.stun_server {
+        config.stun_servers = vec![server];
+    }
+    config.explain_peers.extend(cli_args.explain);
+
+    if cli_args.print_config {
+        print!(
+            "{}",
+            toml::to_string(&config).map_err(map_error!("Failed to print config"))?
+        );
+        return Ok(());
+    }
+
+    let config = Arc::new(config);
 
     if cli_args.validate {
         return cli_args
@@ -60,46 +148,11 @@ pub async fn start(cancellation: utils::CancellationUnit) -> Result<(), ()> {
             .ok_or_else(|| error!("Config file is not specified"));
     }
 
-    // Construct state
-    let router_state = admin_api::connect(config.clone())
-        .await
-        .map_err(|_| error!("Failed to connect to admin socket"))?;
-    let watch_sessions = watch::channel(Vec::new());
-    let watch_peers = watch::channel(Vec::new());
-    let watch_external = watch::channel(Vec::new());
-
-    let state = State::new(StateInner {
-        router: RwLock::new(router_state),
-        watch_external: watch_external.1,
-        watch_sessions: watch_sessions.1,
-        watch_peers: watch_peers.1,
-        active_sessions: RwLock::new(HashMap::new()),
-        active_sockets_tcp: RwLock::new(HashMap::new()),
-        cancellation: cancellation.clone(),
-    });
-
-    // Spawn & wait
-    let external_required = watch::channel(Instant::now());
-    let (external_listeners, external_addresses) =
-        network::create_listener_sockets(config.clone(), state.clone())?;
-
     select! {
-        _ = spawn(network::setup_listeners(config.clone(), state.clone(), external_listeners)) => {},
-        _ = spawn(stun::monitor(config.clone(), state.clone(), external_addresses, watch_external.0, external_required.1)) => {},
-        _ = spawn(admin_api::monitor(
-            config.clone(),
-            state.clone(),
-            watch_sessions.0,
-            watch_peers.0
-        )) => {},
-        _ = spawn(session::spawn_new_sessions(config.clone(), state.clone(), external_required.0)) => {},
-
-        _ = cancellation.cancelled() => {},
+        err = run(config, cancellation, registered_peers) => err.map_err(|err| error!("{err}")),
         _ = tokio::signal::ctrl_c() => {
             warn!("Stop signal received");
-            return Ok(());
+            Ok(())
         },
     }
-
-    Err(())
 }
@@ -71,10 +71,189 @@ impl PeeringProtocol {
     pub fn id(&self) -> &'static str {
         self.into()
     }
+
+    /// Check if any of `yggdrasil_listen` URIs is of this protocol
+    pub fn is_listened_by_router(&self, config: &config::ConfigInner) -> bool {
+        config
+            .yggdrasil_listen
+            .iter()
+            .any(|a| a.split("://").next() == Some(self.id()))
+    }
 }
 
 pub const QUIC_MAXIMUM_PACKET_SIZE: usize = 1500;
 
+/// Query appended to URIs of peers registered by jumper, making them recognizable in `getpeers`
+pub const PEER_URI_MARKER: &str = "source=jumper";
+
+/// Compare peer URIs ignoring their query parameters
+pub fn is_same_peer_uri(a: &str, b: &str) -> bool {
+    a.split('?').next() == b.split('?').next()
+}
+
+/// Errors a connected UDP socket may return without the relay becoming unusable,
+/// e.g. an ICMP port unreachable reported for a previously sent datagram
+fn is_transient_udp_error(err: &IoError) -> bool {
+    matches!(
+        err.kind(),
+        IoErrorKind::ConnectionRefused
+            | IoErrorKind::ConnectionReset
+            | IoErrorKind::WouldBlock
+            | IoErrorKind::TimedOut
+            | IoErrorKind::Interrupted
+    )
+}
+
+/// Traffic relayed in one direction of a bridge
+#[derive(Default)]
+struct RelayCounter {
+    bytes: AtomicU64,
+    packets: AtomicU64,
+}
+
+impl RelayCounter {
+    fn add(&self, len: usize) {
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.packets.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Display for RelayCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} byte(s) in {} packet(s)",
+            self.bytes.load(Ordering::Relaxed),
+            self.packets.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// URIs of peers registered by jumper and not yet removed from the router.
+/// Shared with the caller of `run`, to report them if shutdown times out
+pub type RegisteredPeers = Arc<std::sync::Mutex<std::collections::BTreeSet<String>>>;
+
+/// Register peer on the router, replacing a stale registration of the same address
+pub async fn add_peer(state: &State, uri: &str) -> Result<(), ()> {
+    let admin_api = &mut state.router.write().await.admin_api;
+
+    let stale = admin_api
+        .get_peers()
+        .await
+        .map_err(map_warn!("Failed to query admin api"))?
+        .map_err(map_warn!("Failed to list peers"))?
+        .into_iter()
+        .filter_map(|peer| peer.remote)
+        .filter(|remote| is_same_peer_uri(remote, uri))
+        .collect::<Vec<_>>();
+    for remote in stale {
+        debug!("Removing stale peer {remote}");
+        admin_api
+            .remove_peer(remote, None)
+            .await
+            .map_err(map_warn!("Failed to query admin api"))?
+            .map_err(map_debug!("Failed to remove stale peer"))
+            .ok();
+    }
+
+    admin_api
+        .add_peer(uri.to_string(), None)
+        .await
+        .map_err(map_warn!("Failed to query admin api"))?
+        .map_err(map_warn!("Failed to add local socket as peer"))?;
+    state
+        .registered_peers
+        .lock()
+        .unwrap()
+        .insert(uri.to_string());
+    Ok(())
+}
+
+/// Unregister peer from the router, tolerating it to be already absent
+pub async fn remove_peer(state: &State, uri: &str) -> Result<(), ()> {
+    let admin_api = &mut state.router.write().await.admin_api;
+
+    let Err(err) = admin_api
+        .remove_peer(uri.to_string(), None)
+        .await
+        .map_err(map_debug!("Failed to query admin api"))?
+    else {
+        state.registered_peers.lock().unwrap().remove(uri);
+        return Ok(());
+    };
+
+    // Error wording differs between router versions, so check the peer list instead
+    let listed = admin_api
+        .get_peers()
+        .await
+        .map_err(map_debug!("Failed to query admin api"))?
+        .map_err(map_debug!("Failed to list peers"))?
+        .into_iter()
+        .filter_map(|peer| peer.remote)
+        .any(|remote| is_same_peer_uri(&remote, uri));
+    if listed {
+        return Err(debug!(
+            "Failed to remove local socket from peer list: {err}"
+        ));
+    }
+    debug!("Peer {uri} is already removed");
+    state.registered_peers.lock().unwrap().remove(uri);
+    Ok(())
+}
+
+/// Check if URI is of loopback peer registered by jumper
+pub fn is_jumper_peer_uri(uri: &str) -> bool {
+    let Some((address, query)) = uri.split_once('?') else {
+        return false;
+    };
+    let host = address.split("://").nth(1).and_then(|a| a.rsplit_once(':'));
+    query.split('&').any(|q| q == PEER_URI_MARKER)
+        && host.is_some_and(|(host, _)| host == "127.0.0.1" || host == "[::1]")
+}
+
+/// Remove disconnected jumper peers left on the router, e.g. after crash
+#[instrument(parent = None, name = "Orphaned peers cleanup", skip_all)]
+pub async fn remove_orphaned_peers(state: &State) -> Result<(), ()> {
+    let orphans = state
+        .router
+        .write()
+        .await
+        .admin_api
+        .get_peers()
+        .await
+        .map_err(map_warn!("Failed to query admin api"))?
+        .map_err(map_warn!("Failed to list peers"))?
+        .into_iter()
+        .filter(|peer| !peer.up)
+        .filter_map(|peer| peer.remote)
+        .filter(|remote| is_jumper_peer_uri(remote))
+        .collect::<Vec<_>>();
+
+    for uri in orphans {
+        info!("Removing orphaned peer {uri}");
+        remove_peer(state, &uri).await.ok();
+    }
+    Ok(())
+}
+
+/// Run user command with `args` appended, without awaiting it in the caller
+async fn run_hook(command: Vec<String>, args: [String; 3]) {
+    let Some((program, command_args)) = command.split_first() else {
+        return;
+    };
+    match tokio::process::Command::new(program)
+        .args(command_args)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .status()
+        .await
+    {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("Command {program} exited with {status}"),
+        Err(err) => warn!("Failed to run command {program}: {err}"),
+    }
+}
+
 #[instrument(parent = None, name = "Bridge ", skip_all, fields(peer = ?monitor_address, remote = %peer_addr, uri = %uri))]
 async fn bridge(
     config: Config,
@@ -90,6 +269,14 @@ async fn bridge(
     let cancellation = state.cancellation.clone();
     let mut relays = JoinSet::new();
 
+    // Report relayed traffic when bridge is closed
+    let to_peer = Arc::new(RelayCounter::default());
+    let to_router = Arc::new(RelayCounter::default());
+    let _report_counters = {
+        let (to_peer, to_router) = (to_peer.clone(), to_router.clone());
+        defer(move || info!("Relayed {to_peer} to peer and {to_router} to router"))
+    };
+
     match (peer, ygg) {
         // Relay UDP traffic
         (RouterStream::Tcp(peer), RouterStream::Tcp(ygg)) => {
@@ -97,7 +284,9 @@ async fn bridge(
             let (ygg_read, ygg_write) = ygg.into_split();
 
             use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-            let tcp_relay = |reader: OwnedReadHalf, mut writer: OwnedWriteHalf| async move {
+            let tcp_relay = |reader: OwnedReadHalf,
+                             mut writer: OwnedWriteHalf,
+                             counter: Arc<RelayCounter>| async move {
                 let mut reader = BufReader::new(reader);
                 loop {
                     let buf = reader
@@ -114,16 +303,17 @@ async fn bridge(
                         .await
                         .map_err(map_debug!("Failed to write"))?;
                     trace!("Sent {} byte(s)", len);
+                    counter.add(len);
                     reader.consume(len);
                 }
             };
 
             relays.spawn(
-                tcp_relay(ygg_read, peer_write)
+                tcp_relay(ygg_read, peer_write, to_peer.clone())
                     .instrument(error_span!(" Router -> Peer TCP relay")),
             );
             relays.spawn(
-                tcp_relay(peer_read, ygg_write)
+                tcp_relay(peer_read, ygg_write, to_router.clone())
                     .instrument(error_span!(" Peer -> Router TCP relay")),
             );
         }
@@ -134,28 +324,51 @@ async fn bridge(
             let ygg_read = Arc::new(ygg);
             let ygg_write = ygg_read.clone();
 
-            let udp_relay = |reader: Arc<UdpSocket>, writer: Arc<UdpSocket>| async move {
+            let simulate_loss = cfg!(debug_assertions)
+                .then_some(config.simulate_loss)
+                .filter(|loss| *loss > 0.0);
+            if config.simulate_loss > 0.0 && simulate_loss.is_none() {
+                warn!("Option `simulate_loss` is ignored in release builds");
+            }
+
+            let udp_relay = |reader: Arc<UdpSocket>,
+                             writer: Arc<UdpSocket>,
+                             counter: Arc<RelayCounter>| async move {
                 let mut buf = Box::new([0u8; QUIC_MAXIMUM_PACKET_SIZE]);
                 loop {
-                    let received = reader
-                        .recv(&mut buf[..])
-                        .await
-                        .map_err(map_debug!("Failed to recv"))?;
+                    let received = match reader.recv(&mut buf[..]).await {
+                        Ok(received) => received,
+                        Err(err) if is_transient_udp_error(&err) => {
+                            trace!("Ignored recv error: {err}");
+                            continue;
+                        }
+                        Err(err) => return Err(debug!("Failed to recv: {err}")),
+                    };
 
-                    writer
-                        .send(&buf[..received])
-                        .await
-                        .map_err(map_debug!("Failed to send"))?;
-                    trace!("Sent {} byte(s)", &buf[..received].len());
+                    if simulate_loss.is_some_and(|loss| rand::random::<f32>() < loss) {
+                        trace!("Dropped {} byte(s), simulating loss", received);
+                        continue;
+                    }
+
+                    match writer.send(&buf[..received]).await {
+                        Ok(_) => {
+                            trace!("Sent {} byte(s)", received);
+                            counter.add(received);
+                        }
+                        Err(err) if is_transient_udp_error(&err) => {
+                            trace!("Ignored send error: {err}");
+                        }
+                        Err(err) => return Err(debug!("Failed to send: {err}")),
+                    }
                 }
             };
 
             relays.spawn(
-                udp_relay(peer_read, ygg_write)
+                udp_relay(peer_read, ygg_write, to_router.clone())
                     .instrument(error_span!(" Peer -> Router UDP relay")),
             );
             relays.spawn(
-                udp_relay(ygg_read, peer_write)
+                udp_relay(ygg_read, peer_write, to_peer.clone())
                     .instrument(error_span!(" Router -> Peer UDP relay")),
             );
         }
@@ -166,6 +379,8 @@ async fn bridge(
     let mut watch_peers = state.watch_peers.clone();
     let mut watch_sessions = state.watch_sessions.clone();
     let mut delay_shutdown = Some(Instant::now());
+    let mut session_closed_since: Option<Instant> = None;
+    let started_at = Instant::now();
 
     // Record the bridge
     let old = state
@@ -189,6 +404,16 @@ async fn bridge(
             .remove(&monitor_address);
     });
 
+    // Notify user commands
+    let hook_args = [
+        monitor_address.to_string(),
+        peer_addr.to_string(),
+        uri.clone(),
+    ];
+    spawn(run_hook(config.on_bridge_up.clone(), hook_args.clone()).in_current_span());
+    let _hook_down =
+        defer_async(run_hook(config.on_bridge_down.clone(), hook_args).in_current_span());
+
     // Await bridge unused
     loop {
         select! {
@@ -214,14 +439,14 @@ async fn bridge(
                     && !peers
                         .iter()
                         .filter(|peer| peer.up)
-                        .any(|peer| peer.remote.as_ref() == Some(&uri))
+                        .any(|peer| peer.remote.as_ref().is_some_and(|r| is_same_peer_uri(r, &uri)))
                 {
                     return Err(info!("Bridge is not connected as peer"));
                 }
 
                 // Return if peer is of unexpected address
                 if let Some(connected_address) = peers.iter()
-                        .filter(|peer| peer.remote.as_ref() == Some(&uri))
+                        .filter(|peer| peer.remote.as_ref().is_some_and(|r| is_same_peer_uri(r, &uri)))
                         .filter_map(|peer| peer.address)
                         .find(|address| address != &monitor_address)
                 {
@@ -229,13 +454,41 @@ async fn bridge(
                 }
             },
 
-            // Return if session is closed
+            // Return if session is closed for longer than `session_close_grace`
             err = watch_sessions.changed()  => {
                 err.map_err(|_| ())?;
-                if ! watch_sessions.borrow().iter().any(|session| &session.address == &monitor_address) {
-                    return Err(info!("Associated session is closed"));
+                let is_open = watch_sessions.borrow().iter().any(|session| session.address == monitor_address);
+                match (is_open, session_closed_since) {
+                    (true, Some(_)) => {
+                        debug!("Associated session is reopened");
+                        session_closed_since = None;
+                    }
+                    (false, None) if config.session_close_grace.is_zero() => {
+                        return Err(info!("Associated session is closed"));
+                    }
+                    (false, None) => {
+                        debug!("Associated session is closed, awaiting reopen");
+                        session_closed_since = Some(Instant::now());
+                    }
+                    _ => {}
                 }
             },
+            _ = sleep(config.session_close_grace.saturating_sub(
+                session_closed_since.map(|t| t.elapsed()).unwrap_or_default()
+            )), if session_closed_since.is_some() => {
+                return Err(info!("Associated session is closed"));
+            },
+
+            // Return to let the session be traversed again, NAT mapping may have gone stale.
+            // New bridge can't be set up beforehand, it would be traversed between the same
+            // local port and remote address this one still holds. Traffic is routed over the
+            // yggdrasil network meanwhile
+            _ = sleep(config.bridge_max_lifetime.saturating_sub(started_at.elapsed())),
+                if !config.bridge_max_lifetime.is_zero() => {
+                relays.abort_all();
+                info!("Bridge reached `bridge_max_lifetime`, closing it to traverse anew");
+                return Ok(());
+            },
 
             // Return if cancelled
             _ = cancellation.cancelled() => return Ok(()),
@@ -255,6 +508,24 @@ pub async fn start_bridge(
 ) -> Result<(), ()> {
     debug!("Started");
 
+    // Keep working bridge rather than replacing it, e.g. when both peers initiated the session
+    let bridged = state
+        .active_sessions
+        .read()
+        .await
+        .get(&monitor_address)
+        .is_some_and(SessionType::is_bridge);
+    if bridged {
+        session::record_decision(
+            &config,
+            &mut *state.session_decisions.write().await,
+            monitor_address,
+            SessionDecision::BridgeExists,
+        );
+        info!("Bridge is already running, deferring to it");
+        return Ok(());
+    }
+
     // Generate yggdrasil peer uri for given address and protocol
     let uri = |local_addr| {
         format!(
@@ -271,25 +542,37 @@ pub async fn start_bridge(
         err.map_err(map_warn!("Failed to retrieve local socket address"))
     };
 
-    // Try connect self to the router listen address directly
-    for url in config
-        .yggdrasil_listen
-        .iter()
-        .filter(|_| connection_mode.as_client())
-    {
-        let mut iter = url.as_str().split("://");
-        let prot = iter.next().map(|i| PeeringProtocol::from_str(i));
-        let addr = iter.next().map(|a| a.split("?").next());
-
-        let ygg = match (prot, addr) {
-            (Some(Ok(p)), Some(Some(addr))) if p == protocol => {
-                if p != protocol {
-                    continue;
-                }
-                match protocol {
-                    PeeringProtocol::Tcp | PeeringProtocol::Tls => {
-                        let ygg =
-                            timeout(config.connect_as_client_timeout, TcpStream::connect(addr))
+    for attempt in 0..=config.bridge_setup_retry_count {
+        // Removes registered peer when goes out of scope
+        let mut remove_peer = None;
+
+        let setup = async {
+            // Try connect self to the router listen address directly
+            for url in config
+                .yggdrasil_listen
+                .iter()
+                .filter(|_| connection_mode.as_client())
+            {
+                let mut iter = url.as_str().split("://");
+                let prot = iter.next().map(PeeringProtocol::from_str);
+                let addr = iter.next().map(|a| a.split("?").next());
+
+                let ygg = match (prot, addr) {
+                    (Some(Ok(p)), Some(Some(addr))) if p == protocol => {
+                        if p != protocol {
+                            continue;
+                        }
+                        match protocol {
+                            PeeringProtocol::Tcp | PeeringProtocol::Tls => {
+                                let Ok(addrs) =
+                                    utils::resolve(&config, &state.dns_cache, addr).await
+                                else {
+                                    continue;
+                                };
+                                let ygg = timeout(
+                                    config.connect_as_client_timeout,
+                                    TcpStream::connect(addrs.as_slice()),
+                                )
                                 .await
                                 .map_err(map_warn!(
                                     "Failed to connect to router listen socket at {addr}"
@@ -300,134 +583,148 @@ pub async fn start_bridge(
                                     ))
                                 })
                                 .ok();
-                        let addr = ygg
-                            .as_ref()
-                            .and_then(|ygg| map_addr_err(ygg.local_addr()).ok());
-                        ygg.map(|ygg| ygg.into()).zip(addr.map(|addr| uri(addr)))
-                    }
-                    PeeringProtocol::Quic => {
-                        let addrs = tokio::net::lookup_host(addr)
-                            .await
-                            .map_err(map_warn!("Failed to lookup addr {addr}"))
-                            .ok();
-
-                        let addr = addrs.and_then(|mut a| a.next());
-
-                        if let Some(addr) = addr {
-                            let ygg = utils::create_udp_socket_in_domain(&addr, 0)?;
-                            ygg.connect(addr)
-                                .await
-                                .map_err(map_warn!("Failed to connect UDP socket to {addr}"))
-                                .ok();
-
-                            let addr = map_addr_err(ygg.local_addr()).ok();
-
-                            Some(ygg.into()).zip(addr.map(|addr| uri(addr)))
-                        } else {
-                            None
+                                let addr = ygg
+                                    .as_ref()
+                                    .and_then(|ygg| map_addr_err(ygg.local_addr()).ok());
+                                ygg.map(|ygg| ygg.into()).zip(addr.map(&uri))
+                            }
+                            PeeringProtocol::Quic => {
+                                let addr = utils::resolve(&config, &state.dns_cache, addr)
+                                    .await
+                                    .ok()
+                                    .and_then(|addrs| addrs.into_iter().next());
+
+                                if let Some(addr) = addr {
+                                    let ygg = utils::create_udp_socket_in_domain(&addr, 0)?;
+                                    ygg.connect(addr)
+                                        .await
+                                        .map_err(map_warn!(
+                                            "Failed to connect UDP socket to {addr}"
+                                        ))
+                                        .ok();
+
+                                    let addr = map_addr_err(ygg.local_addr()).ok();
+
+                                    Some(ygg.into()).zip(addr.map(&uri))
+                                } else {
+                                    None
+                                }
+                            }
                         }
                     }
+                    _ => {
+                        debug!("Router address is unavailable: {}", url);
+                        continue;
+                    }
+                };
+
+                if let Some((ygg, uri)) = ygg {
+                    return Ok((ygg, uri));
                 }
             }
-            _ => {
-                debug!("Router address is unavailable: {}", url);
-                continue;
+
+            // Fallback. Try connect router to self temporary socket
+            if !connection_mode.as_server() {
+                warn!("Failed to find suitable server socket");
+                return Err(());
             }
-        };
 
-        if let Some((ygg, uri)) = ygg {
-            return bridge(config, state, monitor_address, peer_addr, socket, ygg, uri).await;
-        }
-    }
+            // Register on the router peer as a server
+            let _state = state.clone();
+            let _remove_peer = &mut remove_peer;
+            let add_peer = |uri: String| async move {
+                // Add peer now
+                self::add_peer(&_state, &uri).await?;
 
-    // Fallback. Try connect router to self temporary socket
-    if !connection_mode.as_server() {
-        warn!("Failed to find suitable server socket");
-        return Err(());
-    }
+                // Remove peer later
+                *_remove_peer = Some(defer_async(async move {
+                    self::remove_peer(&_state, &uri).await
+                }));
 
-    // Register on the router peer as a server
-    let _state = state.clone();
-    let _remove_peer = &mut None;
-    let add_peer = |uri: String| async move {
-        // Add peer now
-        _state
-            .router
-            .write()
-            .await
-            .admin_api
-            .add_peer(uri.clone(), None)
-            .await
-            .map_err(map_warn!("Failed to query admin api"))?
-            .map_err(map_warn!("Failed to add local socket as peer"))?;
-
-        // Remove peer later
-        *_remove_peer = Some(defer_async(async move {
-            _state
-                .router
-                .write()
-                .await
-                .admin_api
-                .remove_peer(uri, None)
-                .await
-                .map_err(map_debug!("Failed to query admin api"))?
-                .map_err(map_debug!("Failed to remove local socket from peer list"))
-        }));
-
-        Ok(())
-    };
+                Ok(())
+            };
+
+            match protocol {
+                PeeringProtocol::Tcp | PeeringProtocol::Tls => {
+                    // Create socket
+                    let ygg = utils::create_tcp_socket_in_domain(&peer_addr, 0)?
+                        .listen(1)
+                        .map_err(map_warn!("Failed to create local inbound socket"))?;
+
+                    // Register socket as a peer
+                    let uri = format!("{}?{PEER_URI_MARKER}", uri(map_addr_err(ygg.local_addr())?));
+                    add_peer(uri.clone()).await?;
+
+                    // Await incoming connection
+                    let (ygg, _) = timeout(config.connect_as_client_timeout, ygg.accept())
+                        .await
+                        .map_err(map_warn!("Failed to accept yggdrasil connection"))?
+                        .map_err(map_warn!("Failed to accept yggdrasil connection"))?;
+
+                    Ok((RouterStream::Tcp(ygg), uri))
+                }
+                PeeringProtocol::Quic => {
+                    // Create socket
+                    let ygg = utils::create_udp_socket_in_domain(&peer_addr, 0)?;
 
-    let (ygg, uri) = match protocol {
-        PeeringProtocol::Tcp | PeeringProtocol::Tls => {
-            // Create socket
-            let ygg = utils::create_tcp_socket_in_domain(&peer_addr, 0)?
-                .listen(1)
-                .map_err(map_warn!("Failed to create local inbound socket"))?;
+                    // Register socket as a peer
+                    let uri = format!("{}?{PEER_URI_MARKER}", uri(map_addr_err(ygg.local_addr())?));
+                    add_peer(uri.clone()).await?;
 
-            // Register socket as a peer
-            let uri = uri(map_addr_err(ygg.local_addr())?);
-            add_peer(uri.clone()).await?;
+                    // Await incoming packets
+                    let sender = timeout(config.connect_as_client_timeout, ygg.peek_sender())
+                        .await
+                        .map_err(map_warn!("Failed to peek yggdrasil connection"))?
+                        .map_err(map_warn!("Failed to peek yggdrasil connection"))?;
+
+                    // Connect socket to the sender of the first received packet
+                    ygg.connect(sender)
+                        .await
+                        .map_err(map_warn!("Failed to connect to yggdrasil socket"))?;
 
-            // Await incoming connection
-            let (ygg, _) = timeout(config.connect_as_client_timeout, ygg.accept())
-                .await
-                .map_err(map_warn!("Failed to accept yggdrasil connection"))?
-                .map_err(map_warn!("Failed to accept yggdrasil connection"))?;
+                    Ok((ygg.into(), uri))
+                }
+            }
+        };
 
-            (RouterStream::Tcp(ygg), uri)
+        match timeout(config.bridge_setup_timeout, setup).await {
+            Ok(Ok((ygg, uri))) => {
+                // Run bridge
+                let result =
+                    bridge(config, state, monitor_address, peer_addr, socket, ygg, uri).await;
+                drop(remove_peer);
+                return result;
+            }
+            Ok(Err(())) => {}
+            Err(_) => warn!("Bridge setup timed out"),
         }
-        PeeringProtocol::Quic => {
-            // Create socket
-            let ygg = utils::create_udp_socket_in_domain(&peer_addr, 0)?;
-
-            // Register socket as a peer
-            let uri = uri(map_addr_err(ygg.local_addr())?);
-            add_peer(uri.clone()).await?;
-
-            // Await incoming packets
-            let sender = timeout(config.connect_as_client_timeout, ygg.peek_sender())
-                .await
-                .map_err(map_warn!("Failed to peek yggdrasil connection"))?
-                .map_err(map_warn!("Failed to peek yggdrasil connection"))?;
-
-            // Connect socket to the sender of the first received packet
-            ygg.connect(sender)
-                .await
-                .map_err(map_warn!("Failed to connect to yggdrasil socket"))?;
-
-            (ygg.into(), uri)
+
+        if attempt < config.bridge_setup_retry_count {
+            debug!("Retrying bridge setup, attempt {} failed", attempt + 1);
         }
-    };
+    }
 
-    // Run bridge
-    bridge(
-        config,
-        state.clone(),
-        monitor_address,
-        peer_addr,
-        socket,
-        ygg,
-        uri.clone(),
-    )
-    .await
+    Err(warn!("Failed to set bridge up"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_uri() {
+        assert!(is_jumper_peer_uri("tcp://127.0.0.1:4000?source=jumper"));
+        assert!(is_jumper_peer_uri("quic://[::1]:4000?key=1&source=jumper"));
+        assert!(!is_jumper_peer_uri("tcp://127.0.0.1:4000"));
+        assert!(!is_jumper_peer_uri("tcp://192.0.2.1:4000?source=jumper"));
+
+        assert!(is_same_peer_uri(
+            "tcp://127.0.0.1:4000",
+            "tcp://127.0.0.1:4000?source=jumper"
+        ));
+        assert!(!is_same_peer_uri(
+            "tcp://127.0.0.1:4000",
+            "tcp://127.0.0.1:4001"
+        ));
+    }
 }
@@ -2,50 +2,132 @@ use super::*;
 
 pub type Config = Arc<ConfigInner>;
 
-#[derive(PartialEq, Debug, Deserialize)]
+#[derive(PartialEq, Debug, Deserialize, Serialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct ConfigInner {
     pub allow_ipv4: bool,
     pub allow_ipv6: bool,
+    pub local_addresses: Vec<IpAddr>,
+    pub freebind: bool,
     pub listen_port: u16,
     pub yggdrasil_listen: Vec<String>,
     pub yggdrasil_admin_listen: Vec<String>,
     pub yggdrasil_protocols: Vec<PeeringProtocol>,
     pub whitelist: Option<HashSet<Ipv6Addr>>,
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub align_uptime_timeout: Duration,
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub inactivity_delay: Duration,
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub inactivity_delay_period: Duration,
     pub stun_randomize: bool,
+    pub assume_port_preserving: bool,
     pub stun_servers: Vec<String>,
+    pub external_ip_http: Vec<String>,
+    pub static_candidates: Vec<SocketAddr>,
+    pub on_bridge_up: Vec<String>,
+    pub on_bridge_down: Vec<String>,
 
     // Fields below are not listed in example config
     pub nat_traversal_tcp_retry_count: u64,
-    #[serde(deserialize_with = "parse_duration")]
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
     pub nat_traversal_tcp_delay: Duration,
-    #[serde(deserialize_with = "parse_duration")]
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
     pub nat_traversal_tcp_timeout: Duration,
 
     pub nat_traversal_udp_retry_count: u64,
-    #[serde(deserialize_with = "parse_duration")]
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
     pub nat_traversal_udp_delay: Duration,
-    #[serde(deserialize_with = "parse_duration")]
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
     pub nat_traversal_udp_timeout: Duration,
 
-    #[serde(deserialize_with = "parse_duration")]
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
     pub stun_tcp_response_timeout: Duration,
 
-    #[serde(deserialize_with = "parse_duration")]
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
     pub stun_udp_response_timeout: Duration,
     pub stun_udp_retry_count: u64,
+    /// Extract mapped address from responses that fail to decode, e.g. due to non-standard attributes
+    pub stun_lenient_decoding: bool,
+    /// Keep TCP connections to STUN servers open between lookups, if servers allow
+    pub stun_tcp_reuse: bool,
+
+    /// Query a second STUN server and compare reported addresses, to detect symmetric NAT
+    pub stun_check_consistency: bool,
+    /// Don't share external address if servers report different ones. Requires `stun_check_consistency`
+    pub stun_suppress_inconsistent: bool,
 
     pub avoid_redundant_peering: bool,
-    #[serde(deserialize_with = "parse_duration")]
+    /// Share Teredo and 6to4 external addresses with peers
+    pub allow_transitional_ipv6: bool,
+    /// Traverse to local address of a peer behind the same NAT. Private interface addresses are shared with peers
+    pub lan_traversal: bool,
+    /// Existing peering slower than this doesn't count as redundant. Zero to count any
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub redundant_peering_max_latency: Duration,
+    /// Delay traversal until session uptime is aligned. Both peers should agree on it
+    pub align_uptime: bool,
+    /// Let router be the server of TLS and QUIC peerings, unless peer prefers it as well
+    pub prefer_server_role: bool,
+    /// Probes sent over traversed UDP path before bridging it. Zero to bridge without probing.
+    /// Both peers should agree on it
+    pub path_probe_count: u64,
+    /// Fraction of lost probes above which traversed path is abandoned
+    pub path_probe_max_loss: f64,
+    /// Probe round-trip time above which traversed path is abandoned. Zero to accept any
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub path_probe_max_rtt: Duration,
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
     pub peer_unconnected_check_delay: Duration,
-    #[serde(deserialize_with = "parse_duration")]
+    /// Keep bridge this long after its session disappears, in case it reappears. Zero to close immediately
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub session_close_grace: Duration,
+    /// Skip sessions younger than this, as they may be short-lived
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub min_session_uptime: Duration,
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
     pub resolve_external_address_delay: Duration,
-    #[serde(deserialize_with = "parse_duration")]
+    /// Interval of checking routes for network change, which triggers new STUN lookup. Zero to disable
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub network_change_check_delay: Duration,
+    /// Don't share external addresses not confirmed for this long. Zero to share regardless of age
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub external_address_ttl: Duration,
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
     pub yggdrasilctl_query_delay: Duration,
-    #[serde(deserialize_with = "parse_duration")]
+    /// Consecutive failed admin api queries tolerated before giving up. Same admin socket is reconnected after I/O errors
+    pub yggdrasilctl_query_retry_count: u64,
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
     pub connect_as_client_timeout: Duration,
-    #[serde(deserialize_with = "parse_duration")]
+    /// Give up bridge setup attempt if router isn't connected this long
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub bridge_setup_timeout: Duration,
+    /// Bridge setup retries made over the same traversed socket. Zero to try once
+    pub bridge_setup_retry_count: u64,
+    /// Give up session if bridge isn't set up this long after connecting started. Zero to wait indefinitely
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub session_total_timeout: Duration,
+    /// Sessions beyond this number wait before traversal. Zero for no limit
+    pub max_concurrent_traversals: usize,
+    /// Close bridge after this long, so it's traversed anew. Zero to keep it indefinitely.
+    /// Traffic goes over the yggdrasil network until the next session attempt sets up a new bridge
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub bridge_max_lifetime: Duration,
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
     pub socket_inactivity_cleanup_delay: Duration,
+    /// Reuse resolved addresses of STUN servers and the router for this long. Zero to resolve every time
+    #[serde(deserialize_with = "parse_duration", serialize_with = "print_duration")]
+    pub dns_cache_ttl: Duration,
+    /// Log why sessions with these addresses are or aren't bridged
+    pub explain_peers: Vec<Ipv6Addr>,
+    /// Never offer QUIC peering to sessions with these addresses, e.g. if UDP is mangled on the path
+    pub disable_quic_for: Vec<Ipv6Addr>,
+    /// Share only external addresses matching these local or external IPs. Empty to share any
+    pub candidates_allow: Vec<IpAddr>,
+    /// Never share external addresses matching these local or external IPs
+    pub candidates_deny: Vec<IpAddr>,
+    /// Fraction of UDP packets bridges randomly drop, for testing. Applied only in debug builds
+    pub simulate_loss: f32,
 }
 
 impl Default for ConfigInner {
@@ -55,36 +137,69 @@ impl Default for ConfigInner {
         struct Defaults {
             allow_ipv4: bool,
             allow_ipv6: bool,
+            local_addresses: Vec<IpAddr>,
+            freebind: bool,
             listen_port: u16,
             yggdrasil_listen: Vec<String>,
             yggdrasil_admin_listen: Vec<String>,
             yggdrasil_protocols: Vec<PeeringProtocol>,
             whitelist: Option<HashSet<Ipv6Addr>>,
+            #[serde(deserialize_with = "parse_duration")]
+            align_uptime_timeout: Duration,
+            #[serde(deserialize_with = "parse_duration")]
+            inactivity_delay: Duration,
+            #[serde(deserialize_with = "parse_duration")]
+            inactivity_delay_period: Duration,
             stun_randomize: bool,
+            assume_port_preserving: bool,
             stun_servers: Vec<String>,
+            external_ip_http: Vec<String>,
+            static_candidates: Vec<SocketAddr>,
+            on_bridge_up: Vec<String>,
+            on_bridge_down: Vec<String>,
         }
         let Defaults {
             allow_ipv4,
             allow_ipv6,
+            local_addresses,
+            freebind,
             listen_port,
             yggdrasil_listen,
             yggdrasil_admin_listen,
             yggdrasil_protocols,
             whitelist,
+            align_uptime_timeout,
+            inactivity_delay,
+            inactivity_delay_period,
             stun_randomize,
+            assume_port_preserving,
             stun_servers,
+            external_ip_http,
+            static_candidates,
+            on_bridge_up,
+            on_bridge_down,
         } = toml::from_str(Self::default_str()).unwrap();
 
         Self {
             allow_ipv4,
             allow_ipv6,
+            local_addresses,
+            freebind,
             listen_port,
             yggdrasil_listen,
             yggdrasil_admin_listen,
             yggdrasil_protocols,
             whitelist,
+            align_uptime_timeout,
+            inactivity_delay,
+            inactivity_delay_period,
             stun_randomize,
+            assume_port_preserving,
             stun_servers,
+            external_ip_http,
+            static_candidates,
+            on_bridge_up,
+            on_bridge_down,
 
             nat_traversal_tcp_retry_count: 5,
             nat_traversal_tcp_delay: Duration::from_secs_f64(1.0),
@@ -97,14 +212,43 @@ impl Default for ConfigInner {
             stun_tcp_response_timeout: Duration::from_secs_f64(5.0),
 
             stun_udp_retry_count: 3,
+            stun_lenient_decoding: true,
+            stun_tcp_reuse: true,
             stun_udp_response_timeout: Duration::from_secs_f64(4.0),
 
+            stun_check_consistency: false,
+            stun_suppress_inconsistent: false,
+
             avoid_redundant_peering: true,
+            allow_transitional_ipv6: false,
+            lan_traversal: false,
+            redundant_peering_max_latency: Duration::from_secs_f64(0.0),
+            align_uptime: true,
+            prefer_server_role: false,
+            path_probe_count: 0,
+            path_probe_max_loss: 0.2,
+            path_probe_max_rtt: Duration::from_secs_f64(0.0),
             peer_unconnected_check_delay: Duration::from_secs_f64(15.0),
+            session_close_grace: Duration::from_secs_f64(0.0),
+            min_session_uptime: Duration::from_secs_f64(0.0),
             resolve_external_address_delay: Duration::from_secs_f64(30.0),
+            network_change_check_delay: Duration::from_secs_f64(10.0),
+            external_address_ttl: Duration::from_secs_f64(0.0),
             yggdrasilctl_query_delay: Duration::from_secs_f64(10.0),
+            yggdrasilctl_query_retry_count: 3,
             connect_as_client_timeout: Duration::from_secs_f64(5.0),
+            bridge_setup_timeout: Duration::from_secs_f64(20.0),
+            bridge_setup_retry_count: 1,
+            session_total_timeout: Duration::from_secs_f64(0.0),
+            max_concurrent_traversals: 0,
+            bridge_max_lifetime: Duration::from_secs_f64(0.0),
             socket_inactivity_cleanup_delay: Duration::from_secs_f64(30.0),
+            dns_cache_ttl: Duration::from_secs_f64(60.0),
+            explain_peers: Vec::new(),
+            disable_quic_for: Vec::new(),
+            candidates_allow: Vec::new(),
+            candidates_deny: Vec::new(),
+            simulate_loss: 0.0,
         }
     }
 }
@@ -114,7 +258,12 @@ impl ConfigInner {
         include_str!("../config.toml")
     }
 
-    pub fn read(path: &Path) -> Result<Self, ()> {
+    /// Read config file without verifying it, as environment may still override fields
+    pub fn read(path: &Path) -> Result<Self, JumperError> {
+        Self::read_inner(path).map_err(|_| JumperError::InvalidConfig)
+    }
+
+    fn read_inner(path: &Path) -> Result<Self, ()> {
         let config = if path == Path::new("-") {
             let mut buf = String::new();
             std::io::Read::read_to_string(&mut std::io::stdin().lock(), &mut buf)
@@ -123,9 +272,62 @@ impl ConfigInner {
         } else {
             std::fs::read_to_string(path).map_err(map_error!("Failed to read config file"))?
         };
-        let config: Self =
-            toml::from_str(config.as_str()).map_err(map_error!("Failed to parse config"))?;
-        config.verify()
+        toml::from_str(config.as_str()).map_err(map_error!("Failed to parse config"))
+    }
+
+    /// Override fields by environment variables and verify the result
+    pub fn apply_env(self) -> Result<Self, JumperError> {
+        self.apply_vars(|name| std::env::var(name).ok())
+            .and_then(Self::verify)
+            .map_err(|_| JumperError::InvalidConfig)
+    }
+
+    /// Verify config that isn't overridden by environment variables
+    pub fn check(self) -> Result<Self, JumperError> {
+        self.verify().map_err(|_| JumperError::InvalidConfig)
+    }
+
+    fn apply_vars(mut self, var: impl Fn(&str) -> Option<String>) -> Result<Self, ()> {
+        fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, ()>
+        where
+            T::Err: std::fmt::Display,
+        {
+            T::from_str(value.trim()).map_err(map_error!("Failed to parse {name}={value:?}"))
+        }
+        fn parse_list<T: FromStr>(name: &str, value: &str) -> Result<Vec<T>, ()>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value
+                .split(',')
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| parse(name, v))
+                .collect()
+        }
+
+        macro_rules! apply {
+            ($name:literal, $field:ident, $parse:ident) => {
+                if let Some(value) = var($name) {
+                    self.$field = $parse($name, &value)?;
+                }
+            };
+        }
+        apply!("JUMPER_ALLOW_IPV4", allow_ipv4, parse);
+        apply!("JUMPER_ALLOW_IPV6", allow_ipv6, parse);
+        apply!("JUMPER_LISTEN_PORT", listen_port, parse);
+        apply!("JUMPER_ADMIN_LISTEN", yggdrasil_admin_listen, parse_list);
+        apply!("JUMPER_YGGDRASIL_LISTEN", yggdrasil_listen, parse_list);
+        apply!("JUMPER_STUN_SERVERS", stun_servers, parse_list);
+        apply!("JUMPER_STATIC_CANDIDATES", static_candidates, parse_list);
+        if let Some(value) = var("JUMPER_WHITELIST") {
+            self.whitelist = Some(
+                parse_list("JUMPER_WHITELIST", &value)?
+                    .into_iter()
+                    .collect(),
+            );
+        }
+
+        Ok(self)
     }
 
     fn verify(self) -> Result<Self, ()> {
@@ -137,10 +339,46 @@ impl ConfigInner {
             error!("IPv4 and IPv6 connectivity disallowed by the configuration");
             return Err(());
         }
+        for server in &self.stun_servers {
+            stun::parse_server(server)?;
+        }
+        for candidate in &self.static_candidates {
+            if candidate.ip().is_unspecified() || candidate.port() == 0 {
+                error!("Static candidate {candidate} must have specific address and port");
+                return Err(());
+            }
+        }
+        if self.freebind && !cfg!(any(target_os = "android", target_os = "linux")) {
+            warn!("Option `freebind` isn't supported on this platform, ignored");
+        }
+        if self.align_uptime_timeout.is_zero() || self.inactivity_delay_period.is_zero() {
+            error!(
+                "Values of `align_uptime_timeout` and `inactivity_delay_period` must be positive"
+            );
+            return Err(());
+        }
+        if self.stun_suppress_inconsistent && !self.stun_check_consistency {
+            warn!("Option `stun_suppress_inconsistent` has no effect unless `stun_check_consistency` is enabled");
+        }
+        if self.inactivity_delay >= self.inactivity_delay_period {
+            error!("Value of `inactivity_delay` must be less than `inactivity_delay_period`");
+            return Err(());
+        }
+        if !(0.0..=1.0).contains(&self.simulate_loss) {
+            error!("Value of `simulate_loss` must be between 0 and 1");
+            return Err(());
+        }
         Ok(self)
     }
 }
 
+fn print_duration<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
 fn parse_duration<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
     use serde::de::Error;
     Duration::try_from_secs_f64(Deserialize::deserialize(deserializer)?).map_err(D::Error::custom)
@@ -154,4 +392,79 @@ mod tests {
     fn defaults() {
         ConfigInner::default();
     }
+
+    #[test]
+    fn environment() {
+        let vars = HashMap::from([
+            ("JUMPER_LISTEN_PORT", " 4702"),
+            ("JUMPER_STUN_SERVERS", "a:3478, b:3478,"),
+            ("JUMPER_WHITELIST", "200::1"),
+        ]);
+        let config = ConfigInner::default()
+            .apply_vars(|name| vars.get(name).map(|v| v.to_string()))
+            .unwrap();
+        assert_eq!(config.listen_port, 4702);
+        assert_eq!(config.stun_servers, ["a:3478", "b:3478"]);
+        assert_eq!(
+            config.whitelist,
+            Some(HashSet::from([Ipv6Addr::from_str("200::1").unwrap()]))
+        );
+
+        let vars = HashMap::from([("JUMPER_LISTEN_PORT", "port")]);
+        assert!(ConfigInner::default()
+            .apply_vars(|name| vars.get(name).map(|v| v.to_string()))
+            .is_err());
+    }
+
+    #[test]
+    fn print() {
+        let config = ConfigInner::default();
+        let printed = toml::to_string(&config).unwrap();
+        assert_eq!(toml::from_str::<ConfigInner>(&printed).unwrap(), config);
+    }
+
+    #[test]
+    fn cadence() {
+        assert!(ConfigInner::default().verify().is_ok());
+
+        let config = ConfigInner {
+            inactivity_delay: Duration::from_secs(300),
+            inactivity_delay_period: Duration::from_secs(300),
+            ..Default::default()
+        };
+        assert!(config.verify().is_err());
+
+        let config = ConfigInner {
+            align_uptime_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(config.verify().is_err());
+
+        assert!(toml::from_str::<ConfigInner>("inactivity_delay = -1.0").is_err());
+
+        let config = ConfigInner {
+            simulate_loss: 1.5,
+            ..Default::default()
+        };
+        assert!(config.verify().is_err());
+    }
+
+    #[test]
+    fn stun_servers() {
+        let config = ConfigInner {
+            stun_servers: vec![
+                "udp://a:3478".into(),
+                "tcp://b:3478".into(),
+                "c:3478".into(),
+            ],
+            ..Default::default()
+        };
+        assert!(config.verify().is_ok());
+
+        let config = ConfigInner {
+            stun_servers: vec!["stuns://a:5349".into()],
+            ..Default::default()
+        };
+        assert!(config.verify().is_err());
+    }
 }
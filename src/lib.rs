@@ -15,7 +15,10 @@ pub use {
         path::{Path, PathBuf},
         rc::Rc,
         str::FromStr,
-        sync::Arc,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
         time::{Duration, Instant},
     },
     strum::IntoEnumIterator,
@@ -52,7 +55,7 @@ pub mod utils;
 pub use admin_api::RouterState;
 pub use bridge::{ConnectionMode, NetworkProtocol, PeeringProtocol, RouterStream};
 pub use config::Config;
-pub use session::SessionType;
+pub use session::{SessionDecision, SessionType};
 pub use stun::ExternalAddress;
 pub use utils::{defer, defer_async, CancellationUnit};
 
@@ -63,6 +66,107 @@ pub struct StateInner {
     pub watch_peers: watch::Receiver<Vec<PeerEntry>>,
     pub active_sessions: RwLock<HashMap<Ipv6Addr, SessionType>>,
     pub active_sockets_tcp: RwLock<HashMap<SocketAddr, TcpStream>>,
+    pub session_decisions: RwLock<HashMap<Ipv6Addr, SessionDecision>>,
+    pub traversals: Arc<tokio::sync::Semaphore>,
+    pub registered_peers: bridge::RegisteredPeers,
+    pub dns_cache: utils::DnsCache,
     pub cancellation: CancellationUnit,
 }
 pub type State = Arc<StateInner>;
+
+impl StateInner {
+    /// Limit concurrent traversals by `max_concurrent_traversals`
+    pub fn traversal_limit(config: &config::ConfigInner) -> Arc<tokio::sync::Semaphore> {
+        Arc::new(tokio::sync::Semaphore::new(Self::max_traversals(config)))
+    }
+
+    /// Number of sessions currently holding a traversal slot
+    pub fn traversals_in_progress(&self, config: &config::ConfigInner) -> usize {
+        Self::max_traversals(config) - self.traversals.available_permits()
+    }
+
+    fn max_traversals(config: &config::ConfigInner) -> usize {
+        match config.max_concurrent_traversals {
+            0 => tokio::sync::Semaphore::MAX_PERMITS,
+            limit => limit,
+        }
+    }
+}
+
+/// Failures reported by the top-level entry points. Details are logged where they occur
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JumperError {
+    InvalidConfig,
+    AdminConnect,
+    ListenerSetup,
+    TaskExited(&'static str),
+}
+
+impl std::fmt::Display for JumperError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidConfig => write!(f, "Invalid config"),
+            Self::AdminConnect => write!(f, "Failed to connect to admin socket"),
+            Self::ListenerSetup => write!(f, "Failed to create listener sockets"),
+            Self::TaskExited(task) => write!(f, "Task exited unexpectedly: {task}"),
+        }
+    }
+}
+
+impl std::error::Error for JumperError {}
+
+/// Connect to the router and run jumper until cancelled or any of its tasks exits.
+/// Peers it registers on the router are tracked in `registered_peers` until removed
+pub async fn run(
+    config: Config,
+    cancellation: CancellationUnit,
+    registered_peers: bridge::RegisteredPeers,
+) -> Result<(), JumperError> {
+    // Construct state
+    let dns_cache = utils::DnsCache::default();
+    let router_state = admin_api::connect(config.clone(), &dns_cache)
+        .await
+        .map_err(|_| JumperError::AdminConnect)?;
+    let watch_sessions = watch::channel(Vec::new());
+    let watch_peers = watch::channel(Vec::new());
+    let watch_external = watch::channel(Vec::new());
+
+    let state = State::new(StateInner {
+        router: RwLock::new(router_state),
+        watch_external: watch_external.1,
+        watch_sessions: watch_sessions.1,
+        watch_peers: watch_peers.1,
+        active_sessions: RwLock::new(HashMap::new()),
+        active_sockets_tcp: RwLock::new(HashMap::new()),
+        session_decisions: RwLock::new(HashMap::new()),
+        traversals: StateInner::traversal_limit(&config),
+        registered_peers,
+        dns_cache,
+        cancellation: cancellation.clone(),
+    });
+
+    // Clean up after previous run
+    bridge::remove_orphaned_peers(&state).await.ok();
+
+    // Spawn & wait
+    let external_required = watch::channel(Instant::now());
+    let (external_listeners, external_addresses) =
+        network::create_listener_sockets(config.clone(), state.clone())
+            .map_err(|_| JumperError::ListenerSetup)?;
+
+    let task = select! {
+        _ = spawn(network::setup_listeners(config.clone(), state.clone(), external_listeners)) => "listeners",
+        _ = spawn(stun::monitor(config.clone(), state.clone(), external_addresses, watch_external.0, external_required.1)) => "stun monitor",
+        _ = spawn(admin_api::monitor(
+            config.clone(),
+            state.clone(),
+            watch_sessions.0,
+            watch_peers.0
+        )) => "admin api monitor",
+        _ = spawn(session::spawn_new_sessions(config.clone(), state.clone(), external_required.0)) => "session spawner",
+
+        _ = cancellation.cancelled() => return Ok(()),
+    };
+
+    Err(JumperError::TaskExited(task))
+}
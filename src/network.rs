@@ -1,6 +1,8 @@
 use super::*;
 
 pub const NAT_TRAVERSAL_HELLO: &str = "nat-traversal-hello";
+pub const PATH_PROBE_REQUEST: &[u8] = b"jumper-probe?";
+pub const PATH_PROBE_REPLY: &[u8] = b"jumper-probe!";
 
 pub fn create_listener_sockets(
     config: Config,
@@ -8,13 +10,29 @@ pub fn create_listener_sockets(
 ) -> Result<(Vec<TcpListener>, Vec<SocketAddr>), ()> {
     // Create socket pool
     let mut sockets = Vec::<TcpSocket>::new();
-    if config.allow_ipv6 {
-        sockets
-            .push(utils::create_tcp_socket_ipv6(0).map_err(|_| warn!("Can't create IPv6 socket"))?);
-    }
-    if config.allow_ipv4 {
-        sockets
-            .push(utils::create_tcp_socket_ipv4(0).map_err(|_| warn!("Can't create IPv4 socket"))?);
+    if !config.local_addresses.is_empty() {
+        for address in &config.local_addresses {
+            if (address.is_ipv4() && config.allow_ipv4) || (address.is_ipv6() && config.allow_ipv6)
+            {
+                sockets.push(
+                    utils::create_tcp_socket(SocketAddr::new(*address, 0), config.freebind)
+                        .map_err(|_| warn!("Can't create socket at {address}"))?,
+                );
+            } else {
+                warn!("Local address {address} is of disallowed range");
+            }
+        }
+    } else {
+        if config.allow_ipv6 {
+            sockets.push(
+                utils::create_tcp_socket_ipv6(0).map_err(|_| warn!("Can't create IPv6 socket"))?,
+            );
+        }
+        if config.allow_ipv4 {
+            sockets.push(
+                utils::create_tcp_socket_ipv4(0).map_err(|_| warn!("Can't create IPv4 socket"))?,
+            );
+        }
     }
 
     if sockets.is_empty() {
@@ -44,6 +62,11 @@ pub fn create_listener_sockets(
     Ok((listeners, local_addresses))
 }
 
+/// Delay after failed accept, avoids busy looping on broken listener
+pub const LISTENER_ERROR_DELAY: Duration = Duration::from_secs(1);
+/// Number of consecutive accept failures after which listener is recreated
+pub const LISTENER_RECREATE_ERROR_COUNT: usize = 10;
+
 // Listen for incoming internet connections
 #[instrument(parent = None, name = "Internet listener ", skip_all)]
 pub async fn setup_listeners(
@@ -78,17 +101,49 @@ pub async fn setup_listeners(
     let mut tasks = JoinSet::new();
 
     // Spawn internet listeners
-    for listener in listeners {
+    for mut listener in listeners {
         let config = config.clone();
         let state = state.clone();
+        let local = listener
+            .local_addr()
+            .map_err(map_error!("Failed to get listener address"))?;
         tasks.spawn(async move {
+            let mut failures = 0usize;
             loop {
                 // Accept connection
-                let (socket, address) = select! {
+                let result = select! {
                     result = listener.accept() => result,
                     _ = state.cancellation.cancelled() => return Ok(()),
-                }
-                .map_err(map_error!("Failed to accept incoming connection"))?;
+                };
+                let (socket, address) = match result {
+                    Ok(accepted) => {
+                        failures = 0;
+                        accepted
+                    }
+                    Err(err) => {
+                        info!("Failed to accept incoming connection: {err}");
+                        failures += 1;
+                        select! {
+                            _ = sleep(LISTENER_ERROR_DELAY) => {},
+                            _ = state.cancellation.cancelled() => return Ok(()),
+                        }
+
+                        // Recreate listener at the same address, e.g. after network outage
+                        if failures >= LISTENER_RECREATE_ERROR_COUNT {
+                            warn!("Listener at {local} keeps failing, recreating it");
+                            if let Ok(recreated) = utils::create_tcp_socket(local, config.freebind)
+                                .and_then(|s| {
+                                    s.listen(128)
+                                        .map_err(map_warn!("Failed to set listen socket up"))
+                                })
+                            {
+                                listener = recreated;
+                                failures = 0;
+                            }
+                        }
+                        continue;
+                    }
+                };
 
                 // Save connection to the list
                 handle_active_tcp_socket(&config, state.clone(), socket, address).await;
@@ -102,6 +157,7 @@ pub async fn setup_listeners(
         .listen(128)
         .map_err(map_error!("Failed to set listener socket up"))?;
 
+    let whitelist_contains = session::whitelist_filter(&config);
     tasks.spawn(async move {
         loop {
             // Accept every incoming connection
@@ -112,15 +168,99 @@ pub async fn setup_listeners(
             .map_err(map_error!("Failed to accept incoming connection"))?;
 
             // Skip if connection isn't ipv6
-            if !address.is_ipv6() {
+            let SocketAddr::V6(peer) = address else {
                 continue;
-            }
+            };
 
             // Check if remote isn't on known port
             if address.port() != config.listen_port {
                 continue;
             }
 
+            // Handle session initiated by remote from the overlay (200::/7),
+            // unless one is already in progress
+            let mut sessions = state.active_sessions.write().await;
+            if (peer.ip().octets()[0] & 0xfe) == 0x02 && !sessions.contains_key(peer.ip()) {
+                let mut decisions = state.session_decisions.write().await;
+
+                // Apply the same checks as to sessions initiated by this side
+                let uptime = state
+                    .watch_sessions
+                    .borrow()
+                    .iter()
+                    .find(|s| &s.address == peer.ip())
+                    .and_then(|s| s.uptime);
+                let skipped = match whitelist_contains
+                    .as_ref()
+                    .is_none_or(|whitelist_contains| whitelist_contains(peer.ip()))
+                {
+                    false => Some(SessionDecision::NotWhitelisted),
+                    true => session::skip_reason(
+                        &config,
+                        &state.watch_peers.borrow(),
+                        *peer.ip(),
+                        uptime,
+                    ),
+                };
+
+                // Remote is about to traverse, so don't keep it waiting for a free slot.
+                // It connects again on the next attempt
+                let traversal = match skipped {
+                    Some(decision) => Err(decision),
+                    None => state
+                        .traversals
+                        .clone()
+                        .try_acquire_owned()
+                        .map_err(|_| SessionDecision::TraversalLimit),
+                };
+                let traversal = match traversal {
+                    Ok(traversal) => traversal,
+                    Err(decision) => {
+                        session::record_decision(&config, &mut decisions, *peer.ip(), decision);
+                        continue;
+                    }
+                };
+                session::record_decision(
+                    &config,
+                    &mut decisions,
+                    *peer.ip(),
+                    SessionDecision::Connecting,
+                );
+                sessions.insert(*peer.ip(), SessionType::Session);
+                drop(decisions);
+                drop(sessions);
+
+                let config = config.clone();
+                let state = state.clone();
+                spawn(async move {
+                    let result = protocol::try_session(
+                        config.clone(),
+                        state.clone(),
+                        socket,
+                        peer,
+                        traversal,
+                    )
+                    .await;
+                    session::record_decision(
+                        &config,
+                        &mut *state.session_decisions.write().await,
+                        *peer.ip(),
+                        match result {
+                            Ok(()) => SessionDecision::SessionClosed,
+                            Err(()) => SessionDecision::SessionFailed,
+                        },
+                    );
+
+                    // Remove handler record
+                    let mut sessions = state.active_sessions.write().await;
+                    if let Some(SessionType::Session) = sessions.get(peer.ip()) {
+                        sessions.remove(peer.ip());
+                    }
+                });
+                continue;
+            }
+            drop(sessions);
+
             handle_active_tcp_socket(&config, state.clone(), socket, address).await;
         }
     });
@@ -134,108 +274,231 @@ pub async fn traverse(
     config: Config,
     state: State,
     protocol: PeeringProtocol,
-    local_port: u16,
+    local: SocketAddr,
     remote: SocketAddr,
     _monitor_addr: Ipv6Addr,
-    mut notify_traversed: Option<oneshot::Sender<()>>,
-    mut check_traversed: Option<oneshot::Receiver<()>>,
+    notify_traversed: Option<oneshot::Sender<()>>,
+    check_traversed: Option<oneshot::Receiver<()>>,
 ) -> IoResult<RouterStream> {
     debug!("Started");
 
-    let cancellation = state.cancellation.clone();
-
     match protocol {
         // Use TCP
-        PeeringProtocol::Tcp | PeeringProtocol::Tls => {
-            let mut last_err = None;
-            for _ in 0..config.nat_traversal_tcp_retry_count {
-                // Check if TCP stream was already received
-                if state.active_sockets_tcp.read().await.contains_key(&remote) {
-                    let entry = state
-                        .active_sockets_tcp
-                        .write()
-                        .await
-                        .remove_entry(&remote)
-                        .unwrap();
-
-                    last_err = Some(Ok(entry.1));
-                    break;
-                } else {
-                    // Try start new connection
-                    let socket = utils::create_tcp_socket_in_domain(&remote, local_port)
-                        .map_err(|_| IoError::last_os_error())?;
-
-                    if let Ok(err) =
-                        timeout(config.nat_traversal_tcp_timeout, socket.connect(remote)).await
-                    {
-                        last_err = Some(err);
-                        break;
-                    }
-                }
-                if cancellation.is_cancelled() {
-                    break;
-                }
-                sleep(config.nat_traversal_tcp_delay).await;
+        PeeringProtocol::Tcp | PeeringProtocol::Tls => traverse_tcp(
+            &config,
+            &state.cancellation,
+            local,
+            remote,
+            Some(&state.active_sockets_tcp),
+        )
+        .await
+        .map(RouterStream::from),
+        // Use UDP
+        PeeringProtocol::Quic => traverse_udp(
+            &config,
+            &state.cancellation,
+            local,
+            remote,
+            notify_traversed,
+            check_traversed,
+        )
+        .await
+        .map(RouterStream::from),
+    }
+}
+
+/// Try TCP simultaneous open, taking the stream from `accepted` if the listener got it first
+pub async fn traverse_tcp(
+    config: &config::ConfigInner,
+    cancellation: &CancellationToken,
+    local: SocketAddr,
+    remote: SocketAddr,
+    accepted: Option<&RwLock<HashMap<SocketAddr, TcpStream>>>,
+) -> IoResult<TcpStream> {
+    let mut last_err = None;
+    for _ in 0..config.nat_traversal_tcp_retry_count {
+        // Check if TCP stream was already received
+        if let Some(stream) = match accepted {
+            Some(accepted) => accepted.write().await.remove(&remote),
+            None => None,
+        } {
+            last_err = Some(Ok(stream));
+            break;
+        }
+
+        // Try start new connection
+        let socket = utils::create_tcp_socket(local, config.freebind)
+            .map_err(|_| IoError::last_os_error())?;
+
+        match timeout(config.nat_traversal_tcp_timeout, socket.connect(remote)).await {
+            // Connection from remote is already accepted by the listener,
+            // or remote socket is not yet bound
+            Ok(Err(err))
+                if matches!(
+                    err.kind(),
+                    IoErrorKind::AddrInUse
+                        | IoErrorKind::AddrNotAvailable
+                        | IoErrorKind::ConnectionRefused
+                ) =>
+            {
+                last_err = Some(Err(err));
             }
-            match last_err {
-                Some(res) => res.map(|s| s.into()),
-                None => Err(IoError::new(IoErrorKind::TimedOut, "Timeout")),
+            Ok(err) => {
+                last_err = Some(err);
+                break;
             }
+            Err(_) => {}
         }
-        // Use UDP
-        PeeringProtocol::Quic => {
-            let socket = utils::create_udp_socket_in_domain(&remote, local_port)
-                .map_err(|_| IoError::last_os_error())?;
-
-            socket
-                .connect(&remote)
-                .await
-                .map_err(|_| IoError::last_os_error())?;
-
-            let mut last_err = None;
-            for _ in 0..config.nat_traversal_udp_retry_count {
-                socket.send(NAT_TRAVERSAL_HELLO.as_bytes()).await?;
-
-                select! {
-                    err = async {
-                        let mut buf = [0u8; NAT_TRAVERSAL_HELLO.as_bytes().len()];
-
-                        loop {
-                            let received = socket.recv(&mut buf).await?;
-
-                            if &buf[..received] == NAT_TRAVERSAL_HELLO.as_bytes() {
-                                if let Some(tx) = notify_traversed.take() {
-                                    tx.send(()).ok();
-                                }
-                            }
+        if cancellation.is_cancelled() {
+            break;
+        }
+        sleep(config.nat_traversal_tcp_delay).await;
+    }
+    match last_err {
+        Some(res) => res,
+        None => Err(IoError::new(IoErrorKind::TimedOut, "Timeout")),
+    }
+}
+
+/// Exchange hello packets until the remote replies.
+/// `notify_traversed` is fired once the hello is received, and traversal succeeds
+/// after the remote reports the same through `check_traversed`
+pub async fn traverse_udp(
+    config: &config::ConfigInner,
+    cancellation: &CancellationToken,
+    local: SocketAddr,
+    remote: SocketAddr,
+    mut notify_traversed: Option<oneshot::Sender<()>>,
+    mut check_traversed: Option<oneshot::Receiver<()>>,
+) -> IoResult<UdpSocket> {
+    let socket = utils::create_connected_udp_socket(local, remote, config.freebind)
+        .map_err(|_| IoError::last_os_error())?;
+
+    let mut last_err = None;
+    for _ in 0..config.nat_traversal_udp_retry_count {
+        socket.send(NAT_TRAVERSAL_HELLO.as_bytes()).await?;
+
+        select! {
+            err = async {
+                let mut buf = [0u8; NAT_TRAVERSAL_HELLO.len()];
+
+                loop {
+                    let received = socket.recv(&mut buf).await?;
+
+                    if &buf[..received] == NAT_TRAVERSAL_HELLO.as_bytes() {
+                        if let Some(tx) = notify_traversed.take() {
+                            tx.send(()).ok();
                         }
-                    } => { last_err = Some(err); },
-                    _ = sleep(config.nat_traversal_udp_timeout) => {},
+                    }
                 }
+            } => { last_err = Some(err); },
+            _ = sleep(config.nat_traversal_udp_timeout) => {},
+        }
 
-                if notify_traversed.is_none()
-                    && check_traversed
-                        .as_mut()
-                        .map(|c| c.try_recv().is_ok())
-                        .unwrap_or(false)
-                {
-                    last_err = Some(Ok(()));
-                }
+        if notify_traversed.is_none()
+            && check_traversed
+                .as_mut()
+                .map(|c| c.try_recv().is_ok())
+                .unwrap_or(false)
+        {
+            last_err = Some(Ok(()));
+        }
+
+        if let Some(Ok(_)) = last_err {
+            break;
+        }
+        if cancellation.is_cancelled() {
+            break;
+        }
 
-                if let Some(Ok(_)) = last_err {
-                    break;
+        sleep(config.nat_traversal_udp_delay).await;
+    }
+
+    match last_err {
+        Some(res) => res.map(|_| socket),
+        None => Err(IoError::new(IoErrorKind::TimedOut, "Timeout")),
+    }
+}
+
+/// Interval between probes sent by `probe_path`
+const PATH_PROBE_INTERVAL: Duration = Duration::from_millis(20);
+/// Time to await replies after the last probe is sent
+const PATH_PROBE_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Quality of traversed path, see `probe_path`
+#[derive(Debug)]
+pub struct PathQuality {
+    /// Fraction of probes left unanswered
+    pub loss: f64,
+    /// Median round-trip time of answered probes
+    pub rtt: Option<Duration>,
+}
+
+/// Time `probe_path` takes to send `count` probes and await their replies
+pub fn path_probe_duration(count: u64) -> Duration {
+    PATH_PROBE_INTERVAL * count as u32 + PATH_PROBE_REPLY_TIMEOUT
+}
+
+/// Send `count` probes over traversed UDP socket, echoing peer's ones meanwhile.
+/// Peer may probe later, so keep echoing with `answer_path_probes` until it's done
+pub async fn probe_path(socket: &UdpSocket, count: u64) -> IoResult<PathQuality> {
+    let started = Instant::now();
+    let deadline = started + path_probe_duration(count);
+    let mut sent_at = Vec::<Instant>::new();
+    let mut rtts = Vec::<Option<Duration>>::new();
+    let mut buf = [0u8; 64];
+
+    loop {
+        let next = started + PATH_PROBE_INTERVAL * sent_at.len() as u32;
+        select! {
+            _ = tokio::time::sleep_until(next.into()), if (sent_at.len() as u64) < count => {
+                let mut probe = PATH_PROBE_REQUEST.to_vec();
+                probe.extend((sent_at.len() as u64).to_be_bytes());
+                socket.send(&probe).await?;
+                sent_at.push(Instant::now());
+                rtts.push(None);
+            },
+            received = socket.recv(&mut buf) => {
+                let received = &buf[..received?];
+                if answer_path_probe(socket, received).await? {
+                    continue;
                 }
-                if cancellation.is_cancelled() {
-                    break;
+                if let Some(seq) = received.strip_prefix(PATH_PROBE_REPLY) {
+                    let Ok(seq) = <[u8; 8]>::try_from(seq) else { continue };
+                    let seq = u64::from_be_bytes(seq) as usize;
+                    if let (Some(rtt @ None), Some(at)) = (rtts.get_mut(seq), sent_at.get(seq)) {
+                        *rtt = Some(at.elapsed());
+                    }
                 }
+            },
+            _ = tokio::time::sleep_until(deadline.into()) => break,
+        }
+    }
 
-                sleep(config.nat_traversal_udp_delay).await;
-            }
+    let mut answered: Vec<Duration> = rtts.into_iter().flatten().collect();
+    answered.sort();
+    Ok(PathQuality {
+        loss: 1.0 - answered.len() as f64 / count.max(1) as f64,
+        rtt: answered.get(answered.len() / 2).copied(),
+    })
+}
 
-            match last_err {
-                Some(res) => res.map(|_| socket.into()),
-                None => Err(IoError::new(IoErrorKind::TimedOut, "Timeout")),
-            }
-        }
+/// Echo probes peer sends by `probe_path`. Never completes unless socket fails
+pub async fn answer_path_probes(socket: &UdpSocket) -> IoResult<Infallible> {
+    let mut buf = [0u8; 64];
+    loop {
+        let received = socket.recv(&mut buf).await?;
+        answer_path_probe(socket, &buf[..received]).await?;
     }
 }
+
+/// Reply to the `received` packet if it's a probe
+async fn answer_path_probe(socket: &UdpSocket, received: &[u8]) -> IoResult<bool> {
+    let Some(seq) = received.strip_prefix(PATH_PROBE_REQUEST) else {
+        return Ok(false);
+    };
+    let mut reply = PATH_PROBE_REPLY.to_vec();
+    reply.extend(seq);
+    socket.send(&reply).await?;
+    Ok(true)
+}
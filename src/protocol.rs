@@ -19,24 +19,53 @@ use super::*;
  * All commination is in length-delimited JSON packets using `tokio_util::codec::LengthDelimitedCodec`.
 */
 
-/// Align connection time with session's uptime to simultaneously start firewall traversal
-pub const ALIGN_UPTIME_TIMEOUT: f64 = 20.0;
-
-/// Time to wait for inactive session to close
-pub const INACTIVITY_DELAY: f64 = 1.5 * 60.0;
-pub const INACTIVITY_DELAY_PERIOD: f64 = 5.0 * 60.0;
-
 pub const VERSION: &str = "yggdrasil-jumper-v0.1";
 
 pub const TRAVERSAL_SUCCEED: &str = "traversal-succeed";
+pub const PATH_PROBE_READY: &str = "path-probe-ready";
+pub const PATH_ACCEPTED: &str = "path-accepted";
+pub const PATH_REJECTED: &str = "path-rejected";
+
+/// Time to await peer's path probe status, on top of the probing itself
+const PATH_PROBE_SYNC_TIMEOUT: Duration = Duration::from_secs(10);
 
+// Unknown fields are allowed, so optional ones can be added without breaking compatibility.
+// Optional fields are omitted if they hold defaults, since earlier jumpers reject unknown ones
 #[derive(Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
 struct Header {
     version: String,
     ipv4: bool,
     ipv6: bool,
     protocols: Vec<HeaderRouterProtocol>,
+    /// Omitted if cadence is the default one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cadence: Option<HeaderCadence>,
+    /// Local addresses of external ones and private interface addresses, used to reach peer in the local network
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    lan: Vec<SocketAddr>,
+    /// Whether router prefers to be the server of peering, if both sides can be
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    prefer_server: bool,
+}
+
+/// Session cadence parameters, in seconds. Traversal attempts line up only if both sides agree
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct HeaderCadence {
+    align_uptime: bool,
+    align_uptime_timeout: f64,
+    inactivity_delay: f64,
+    inactivity_delay_period: f64,
+}
+
+impl HeaderCadence {
+    fn new(config: &config::ConfigInner) -> Self {
+        Self {
+            align_uptime: config.align_uptime,
+            align_uptime_timeout: config.align_uptime_timeout.as_secs_f64(),
+            inactivity_delay: config.inactivity_delay.as_secs_f64(),
+            inactivity_delay_period: config.inactivity_delay_period.as_secs_f64(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, EnumString, EnumIter, IntoStaticStr)]
@@ -88,20 +117,117 @@ impl HeaderRouterProtocol {
     }
 }
 
+/// Local addresses with ports external addresses are mapped from, and private interface addresses of listeners
+fn lan_candidates(config: &Config, state: &State) -> Vec<SocketAddr> {
+    let interfaces = utils::local_interface_addresses();
+    let addresses = fresh_external(config, state);
+    addresses
+        .iter()
+        .flat_map(|a| {
+            // Private interface addresses the listener socket is reachable on
+            let private = interfaces
+                .iter()
+                .filter(|ip| ip.is_ipv4() == a.local.is_ipv4() && is_private(ip))
+                .filter(|ip| a.local.ip().is_unspecified() || a.local.ip() == **ip)
+                .map(|ip| SocketAddr::new(*ip, a.local.port()))
+                .collect::<Vec<_>>();
+            utils::route_source(a.local.ip(), a.external)
+                .map(|ip| SocketAddr::new(ip, a.local.port()))
+                .into_iter()
+                .chain(private)
+        })
+        .unique()
+        .collect()
+}
+
+/// External addresses confirmed within `external_address_ttl`, NAT may have remapped older ones
+fn fresh_external(config: &Config, state: &State) -> Vec<ExternalAddress> {
+    let addresses = state.watch_external.borrow();
+    let (fresh, stale): (Vec<_>, Vec<_>) = addresses.iter().cloned().partition(|a| {
+        config.external_address_ttl.is_zero()
+            || a.resolved_at.elapsed() < config.external_address_ttl
+    });
+    for address in stale {
+        debug!(
+            "Ignored external address {} not confirmed for {:?}",
+            address.external,
+            address.resolved_at.elapsed()
+        );
+    }
+    fresh
+}
+
+/// Whether address is of RFC 1918 or ULA (fc00::/7) range
+fn is_private(address: &IpAddr) -> bool {
+    match address {
+        IpAddr::V4(ip) => ip.is_private(),
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xfe00) == 0xfc00,
+    }
+}
+
+/// Whether addresses are private (RFC 1918 or ULA) and share /24 or /64 prefix respectively
+fn is_same_private_subnet(a: &IpAddr, b: &IpAddr) -> bool {
+    is_private(a)
+        && is_private(b)
+        && match (a, b) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..3] == b.octets()[..3],
+            (IpAddr::V6(a), IpAddr::V6(b)) => a.segments()[..4] == b.segments()[..4],
+            _ => false,
+        }
+}
+
+/// Whether address is of Teredo (2001::/32) or 6to4 (2002::/16) range, these are rarely traversable
+pub fn is_transitional_ipv6(address: &SocketAddr) -> bool {
+    match address.ip() {
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            (segments[0] == 0x2001 && segments[1] == 0) || segments[0] == 0x2002
+        }
+        IpAddr::V4(_) => false,
+    }
+}
+
 #[instrument(parent = None, name = "Session ", skip_all, fields(peer = %address))]
 pub async fn try_session(
     config: Config,
     state: State,
     socket: TcpStream,
     address: SocketAddrV6,
+    traversal: tokio::sync::OwnedSemaphorePermit,
+) -> Result<(), ()> {
+    // Session and its bridge are tied to the router address, close them once it changes.
+    // Address is changed while admin api is locked, so checking it first drops the session
+    // before it may start a request it couldn't finish
+    let address_changed = state.router.read().await.address_changed.clone();
+    select! {
+        biased;
+        _ = address_changed.cancelled() => Err(info!("Router address changed, closing session")),
+        result = run_session(config, state, socket, address, traversal) => result,
+    }
+}
+
+/// Run the protocol over the overlay `socket`. Traversal slot is taken by the caller beforehand,
+/// so the remote isn't left traversing alone while this side waits for it
+async fn run_session(
+    config: Config,
+    state: State,
+    socket: TcpStream,
+    address: SocketAddrV6,
+    traversal: tokio::sync::OwnedSemaphorePermit,
 ) -> Result<(), ()> {
     let (mut sink, mut stream) = Framed::new(socket, LengthDelimitedCodec::new()).split();
 
     // 0. Select available external IP address ranges
+    let usable =
+        |address: &SocketAddr| config.allow_transitional_ipv6 || !is_transitional_ipv6(address);
     let (ipv6, ipv4) = {
-        let addresses = state.watch_external.borrow();
+        let addresses = fresh_external(&config, &state);
         (
-            config.allow_ipv6 && addresses.iter().map(|a| a.external).any(|a| a.is_ipv6()),
+            config.allow_ipv6
+                && addresses
+                    .iter()
+                    .map(|a| a.external)
+                    .any(|a| a.is_ipv6() && usable(&a)),
             config.allow_ipv4 && addresses.iter().map(|a| a.external).any(|a| a.is_ipv4()),
         )
     };
@@ -109,19 +235,18 @@ pub async fn try_session(
     // 1. Select available router protocols
     let self_protocols: Vec<HeaderRouterProtocol> = {
         let router_version = state.router.read().await.version;
-        let addresses = state.watch_external.borrow();
-        let server_available = |protocol: PeeringProtocol| {
-            config
-                .yggdrasil_listen
-                .iter()
-                .any(|a| a.split("://").next() == Some(protocol.id()))
-        };
+        let addresses = fresh_external(&config, &state);
+        let server_available = |protocol: PeeringProtocol| protocol.is_listened_by_router(&config);
 
         config
             .yggdrasil_protocols
             .iter()
             .filter(|p| addresses.iter().any(|a| a.protocol == (**p).into()))
             .filter_map(|p| p.is_supported_by_router(router_version).then_some(*p))
+            // UDP may be mangled on the path to some peers
+            .filter(|p| {
+                *p != PeeringProtocol::Quic || !config.disable_quic_for.contains(address.ip())
+            })
             .map(|protocol| match protocol {
                 PeeringProtocol::Tcp => HeaderRouterProtocol::Tcp,
                 PeeringProtocol::Tls => HeaderRouterProtocol::Tls {
@@ -135,12 +260,22 @@ pub async fn try_session(
     };
 
     // 2. Send `header` to peer
+    // Private addresses aren't disclosed to peers unless they're used
+    let self_lan = match config.lan_traversal {
+        true => lan_candidates(&config, &state),
+        false => Vec::new(),
+    };
+    let self_cadence = HeaderCadence::new(&config);
+    let default_cadence = HeaderCadence::new(&config::ConfigInner::default());
     sink.send(bytes::Bytes::from(
         serde_json::to_vec(&protocol::Header {
             version: protocol::VERSION.to_string(),
-            ipv4: ipv4,
-            ipv6: ipv6,
+            ipv4,
+            ipv6,
             protocols: self_protocols.clone(),
+            cadence: Some(self_cadence).filter(|c| *c != default_cadence),
+            lan: self_lan.clone(),
+            prefer_server: config.prefer_server_role,
         })
         .expect("Protocol request header can't be serialized"),
     ))
@@ -157,13 +292,21 @@ pub async fn try_session(
     ))
     .map_err(map_info!("Failed to parse incoming header"))?;
 
-    // 4. Check if version is correct
+    // 4. Check if version is correct. Header of other versions is still understood,
+    // as long as it has the fields this version requires
     if remote_header.version != protocol::VERSION {
-        return Err(info!(
+        warn!(
             "Protocol version mismatch: expected: {:?}, received: {:?}",
-            remote_header.version,
-            protocol::VERSION
-        ));
+            protocol::VERSION,
+            remote_header.version
+        );
+    }
+
+    // Warn if session cadence differs, it makes traversal attempts unsynchronized
+    let remote_cadence = remote_header.cadence.unwrap_or(default_cadence);
+    if remote_cadence != self_cadence {
+        warn!("Session cadence mismatch: self {self_cadence:?}, remote {remote_cadence:?}");
+        warn!("Help: Set `align_uptime_timeout`, `inactivity_delay` and `inactivity_delay_period` to the same values on both sides");
     }
 
     // 5. Check if protocol lists are intersected
@@ -174,35 +317,46 @@ pub async fn try_session(
             .find(|remote_protocol| (*self_protocol).compatible(**remote_protocol))
             .map(|remote_protocol| (*self_protocol, *remote_protocol))
     });
-    let (self_protocol, remote_protocol) = HeaderRouterProtocol::choose_with_highest_priority(protocols)
-        .ok_or(())
-        .map_err(|_| info!(
-            "Can't find common router transmit protocols with remote:\n self {self_protocols:#?}, remote: {:#?}",
-            remote_header.protocols
-        ))?;
+    let (self_protocol, remote_protocol) =
+        HeaderRouterProtocol::choose_with_highest_priority(protocols).ok_or_else(|| {
+            info!(
+                "Can't find common router transmit protocols with remote: self {self_protocols:?}, remote: {:?}",
+                remote_header.protocols
+            );
+            let all = self_protocols.iter().chain(remote_header.protocols.iter());
+            for protocol in all.map(|p| PeeringProtocol::from(*p)).unique_by(|p| p.id()) {
+                let find = |protocols: &Vec<HeaderRouterProtocol>| {
+                    protocols
+                        .iter()
+                        .find(|p| PeeringProtocol::from(**p) == protocol)
+                        .copied()
+                };
+                match (find(&self_protocols), find(&remote_header.protocols)) {
+                    (Some(_), None) => info!("Protocol {protocol:?} is not available on remote"),
+                    (None, Some(_)) => info!("Protocol {protocol:?} is not available locally"),
+                    _ => info!(
+                        "Protocol {protocol:?} requires server, but neither side has `yggdrasil_listen` URI for it"
+                    ),
+                }
+            }
+        })?;
 
     // 6. Check if address ranges are intersected
     let external = (|| {
         if ipv6 && remote_header.ipv6 {
-            if let Some(external) = state
-                .watch_external
-                .borrow()
+            if let Some(external) = fresh_external(&config, &state)
                 .iter()
-                .filter(|e| e.external.is_ipv6())
-                .filter(|e| e.protocol == PeeringProtocol::from(self_protocol).into())
-                .next()
+                .filter(|e| e.external.is_ipv6() && usable(&e.external))
+                .find(|e| e.protocol == PeeringProtocol::from(self_protocol).into())
             {
                 return Ok(external.external);
             }
         }
         if ipv4 && remote_header.ipv4 {
-            if let Some(external) = state
-                .watch_external
-                .borrow()
+            if let Some(external) = fresh_external(&config, &state)
                 .iter()
                 .filter(|e| e.external.is_ipv4())
-                .filter(|e| e.protocol == PeeringProtocol::from(self_protocol).into())
-                .next()
+                .find(|e| e.protocol == PeeringProtocol::from(self_protocol).into())
             {
                 return Ok(external.external);
             }
@@ -244,18 +398,46 @@ pub async fn try_session(
     }
 
     // 11. Create message pipe for traversal process
-    let local = state
-        .watch_external
-        .borrow()
+    let local = {
+        let addresses = fresh_external(&config, &state);
+        addresses
+            .iter()
+            .find(|addr| addr.external == external)
+            .ok_or_else(|| {
+                info!(
+                    "Expected external address unavailable: {external}, available: [{}]",
+                    addresses
+                        .iter()
+                        .map(|a| format!("{} (local {}, {:?})", a.external, a.local, a.protocol))
+                        .join(", ")
+                )
+            })?
+            .local
+    };
+    // Local addresses are used only behind the same NAT, private ranges of unrelated networks may overlap.
+    // Both sides come to the same decision, since `lan` is empty unless `lan_traversal` is enabled
+    let remote_lan = remote_header
+        .lan
         .iter()
-        .find(|addr| addr.external == external)
-        .ok_or_else(|| info!("Expected external address unavailable: {external}"))?
-        .local;
-    let remote = remote_external;
+        .filter(|lan| lan.is_ipv4() == remote_external.is_ipv4());
+    let same_subnet = remote_lan.clone().find(|lan| {
+        self_lan
+            .iter()
+            .any(|own| is_same_private_subnet(&own.ip(), &lan.ip()))
+    });
+    let remote = match same_subnet.or(remote_lan.clone().next()) {
+        // NAT may not support hairpinning, reach peer directly in the local network
+        Some(lan) if !self_lan.is_empty() && external.ip() == remote_external.ip() => {
+            info!("Peer is behind the same NAT, traversing to its local address {lan}");
+            *lan
+        }
+        _ => remote_external,
+    };
 
+    // Message pipe is handed back once traversal completes, it's used to agree on path quality
     let notify_traversed = oneshot::channel::<()>();
-    spawn(async move {
-        if let Ok(_) = notify_traversed.1.await {
+    let sink = spawn(async move {
+        if notify_traversed.1.await.is_ok() {
             sink.send(
                 serde_json::to_vec(TRAVERSAL_SUCCEED)
                     .expect("String can't be serialized")
@@ -265,11 +447,11 @@ pub async fn try_session(
             .map_err(map_info!("Failed to send self external addresses to peer"))?;
         }
 
-        Result::<(), ()>::Ok(())
+        Result::<_, ()>::Ok(sink)
     });
 
     let mut check_traversed = oneshot::channel::<()>();
-    spawn(async move {
+    let stream = spawn(async move {
         let response = select! {
             response = stream.next() => {
                 response.ok_or_else(|| {
@@ -286,11 +468,11 @@ pub async fn try_session(
         if status == TRAVERSAL_SUCCEED {
             check_traversed.0.send(()).ok();
 
-            Result::<(), ()>::Ok(())
+            Result::<_, ()>::Ok(stream)
         } else {
             info!("Received unknown peer's connection status");
 
-            Result::<(), ()>::Err(())
+            Result::<_, ()>::Err(())
         }
     });
 
@@ -300,7 +482,14 @@ pub async fn try_session(
             PeeringProtocol::Tcp => ConnectionMode::Any,
             PeeringProtocol::Tls | PeeringProtocol::Quic => {
                 if self_protocol.server_available() == remote_protocol.server_available() {
-                    if address.ip() < &state.router.read().await.address {
+                    // Side preferring to be the server takes it, otherwise decide by address
+                    if config.prefer_server_role != remote_header.prefer_server {
+                        if config.prefer_server_role {
+                            ConnectionMode::AsClient
+                        } else {
+                            ConnectionMode::AsServer
+                        }
+                    } else if address.ip() < &state.router.read().await.address {
                         ConnectionMode::AsClient
                     } else {
                         ConnectionMode::AsServer
@@ -321,7 +510,7 @@ pub async fn try_session(
         config.clone(),
         state.clone(),
         self_protocol.into(),
-        local.port(),
+        local,
         remote,
         *address.ip(),
         Some(notify_traversed.0),
@@ -330,6 +519,16 @@ pub async fn try_session(
     .await
     .map_err(map_debug!("NAT traversal failed"))?;
 
+    // Check quality of the traversed path, route over yggdrasil network may be better
+    if let (RouterStream::Udp(socket), true) = (&socket, config.path_probe_count > 0) {
+        let (Ok(Ok(mut sink)), Ok(Ok(mut stream))) = (sink.await, stream.await) else {
+            return Err(info!("Message pipe is closed after traversal"));
+        };
+        check_path(&config, socket, &mut sink, &mut stream).await?;
+    }
+
+    drop(traversal);
+
     // 14. Start router bridge
     bridge::start_bridge(
         config,
@@ -342,3 +541,103 @@ pub async fn try_session(
     )
     .await
 }
+
+/// Probe traversed path and agree with peer on whether to bridge it.
+/// Sides leave traversal at different moments, so probing starts once both are ready,
+/// and peer's probes are answered until it reports its verdict
+async fn check_path<Si, St>(
+    config: &config::ConfigInner,
+    socket: &UdpSocket,
+    sink: &mut Si,
+    stream: &mut St,
+) -> Result<(), ()>
+where
+    Si: futures::Sink<bytes::Bytes, Error = IoError> + Unpin,
+    St: futures::Stream<Item = IoResult<bytes::BytesMut>> + Unpin,
+{
+    // Start probing once peer is ready as well
+    send_status(sink, PATH_PROBE_READY)
+        .await
+        .map_err(map_info!("Failed to send path status to peer"))?;
+    if receive_status(stream, socket, PATH_PROBE_SYNC_TIMEOUT).await? != PATH_PROBE_READY {
+        return Err(info!("Received unknown peer's path status"));
+    }
+
+    let quality = network::probe_path(socket, config.path_probe_count)
+        .await
+        .map_err(map_info!("Failed to probe traversed path"))?;
+    debug!("Traversed path quality: {quality:?}");
+    let accepted = if quality.loss > config.path_probe_max_loss {
+        info!(
+            "Traversed path loses {:.0}% of packets, keeping existing route",
+            quality.loss * 100.0
+        );
+        false
+    } else if let Some(rtt) = quality
+        .rtt
+        .filter(|rtt| !config.path_probe_max_rtt.is_zero() && *rtt > config.path_probe_max_rtt)
+    {
+        info!("Traversed path round-trip time is {rtt:?}, keeping existing route");
+        false
+    } else {
+        true
+    };
+
+    // Bridge only if both sides accept the path
+    send_status(
+        sink,
+        if accepted {
+            PATH_ACCEPTED
+        } else {
+            PATH_REJECTED
+        },
+    )
+    .await
+    .map_err(map_info!("Failed to send path status to peer"))?;
+    let wait = PATH_PROBE_SYNC_TIMEOUT + network::path_probe_duration(config.path_probe_count);
+    match receive_status(stream, socket, wait).await?.as_str() {
+        _ if !accepted => Err(()),
+        PATH_ACCEPTED => Ok(()),
+        PATH_REJECTED => Err(info!(
+            "Peer rejected traversed path, keeping existing route"
+        )),
+        _ => Err(info!("Received unknown peer's path status")),
+    }
+}
+
+async fn send_status(
+    sink: &mut (impl futures::Sink<bytes::Bytes, Error = IoError> + Unpin),
+    status: &str,
+) -> IoResult<()> {
+    sink.send(
+        serde_json::to_vec(status)
+            .expect("String can't be serialized")
+            .into(),
+    )
+    .await
+}
+
+/// Receive peer's path status, answering its probes meanwhile
+async fn receive_status(
+    stream: &mut (impl futures::Stream<Item = IoResult<bytes::BytesMut>> + Unpin),
+    socket: &UdpSocket,
+    wait: Duration,
+) -> Result<String, ()> {
+    let receive = async {
+        let response = stream
+            .next()
+            .await
+            .ok_or_else(|| info!("Failed to receive peer's path status: Connection closed"))?
+            .map_err(map_info!("Failed to receive peer's path status"))?;
+        serde_json::from_slice::<String>(&response)
+            .map_err(map_info!("Failed to parse peer's path status"))
+    };
+    select! {
+        status = timeout(wait, receive) => {
+            status.map_err(map_info!("Failed to receive peer's path status"))?
+        }
+        err = network::answer_path_probes(socket) => {
+            Err(info!("Failed to answer path probes: {}", err.unwrap_err()))
+        }
+    }
+}
@@ -15,6 +15,56 @@ impl SessionType {
     }
 }
 
+/// Latest reason a session is or isn't being bridged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionDecision {
+    NotWhitelisted,
+    TooYoung,
+    AlreadyPeered,
+    Connecting,
+    InactivityDelay,
+    TraversalLimit,
+    TraversalFailed,
+    TimedOut,
+    SessionFailed,
+    SessionClosed,
+    Bridged,
+    BridgeExists,
+}
+
+impl std::fmt::Display for SessionDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::NotWhitelisted => "Skipped, address is not in the whitelist",
+            Self::TooYoung => "Skipped, session is younger than `min_session_uptime`",
+            Self::AlreadyPeered => "Skipped, already peered directly",
+            Self::Connecting => "Connecting to remote jumper",
+            Self::InactivityDelay => "Waiting, inactivity delay is enacted",
+            Self::TraversalLimit => "Deferred, `max_concurrent_traversals` is reached",
+            Self::TraversalFailed => "Failed to connect to remote jumper",
+            Self::TimedOut => "Bridge wasn't set up within `session_total_timeout`",
+            Self::SessionFailed => "Session failed",
+            Self::SessionClosed => "Bridge closed",
+            Self::Bridged => "Bridged",
+            Self::BridgeExists => "Kept existing bridge, new attempt deferred",
+        })
+    }
+}
+
+/// Update the latest decision, logging it if address is in `explain_peers`
+pub fn record_decision(
+    config: &config::ConfigInner,
+    decisions: &mut HashMap<Ipv6Addr, SessionDecision>,
+    address: Ipv6Addr,
+    decision: SessionDecision,
+) {
+    if decisions.insert(address, decision) != Some(decision)
+        && config.explain_peers.contains(&address)
+    {
+        info!("Peer {address}: {decision}");
+    }
+}
+
 #[instrument(parent = None, name = "Session ", skip_all, fields(peer = %address))]
 async fn connect_session(
     config: Config,
@@ -22,26 +72,89 @@ async fn connect_session(
     address: SocketAddrV6,
     uptime: Option<f64>,
 ) -> Result<(), ()> {
+    let inactivity_delay = config.inactivity_delay.as_secs_f64();
+    let inactivity_delay_period = config.inactivity_delay_period.as_secs_f64();
+
     // Return if inactivity delay is enacted
     if let Some(uptime) = uptime {
-        if uptime > protocol::INACTIVITY_DELAY_PERIOD
-            && uptime % protocol::INACTIVITY_DELAY_PERIOD < protocol::INACTIVITY_DELAY
-        {
-            if Duration::from_secs_f64(uptime % protocol::INACTIVITY_DELAY_PERIOD)
+        if uptime > inactivity_delay_period && uptime % inactivity_delay_period < inactivity_delay {
+            if Duration::from_secs_f64(uptime % inactivity_delay_period)
                 < config.yggdrasilctl_query_delay
             {
                 debug!("Enacting inactivity delay");
             }
+            record_decision(
+                &config,
+                &mut *state.session_decisions.write().await,
+                *address.ip(),
+                SessionDecision::InactivityDelay,
+            );
             return Ok(());
         }
     }
 
+    let attempt = attempt_session(config.clone(), state.clone(), address, uptime);
+    if config.session_total_timeout.is_zero() {
+        return attempt.await;
+    }
+    let mut attempt = std::pin::pin!(attempt);
+    select! {
+        result = &mut attempt => return result,
+        _ = sleep(config.session_total_timeout) => {},
+    }
+
+    // Keep running bridge, it's closed along with the session
+    if state
+        .active_sessions
+        .read()
+        .await
+        .get(address.ip())
+        .is_some_and(SessionType::is_bridge)
+    {
+        return attempt.await;
+    }
+    record_decision(
+        &config,
+        &mut *state.session_decisions.write().await,
+        *address.ip(),
+        SessionDecision::TimedOut,
+    );
+    Err(info!(
+        "Session exceeded `session_total_timeout` before bridge was set up"
+    ))
+}
+
+/// Traverse to remote jumper and run the session
+async fn attempt_session(
+    config: Config,
+    state: State,
+    address: SocketAddrV6,
+    uptime: Option<f64>,
+) -> Result<(), ()> {
+    let align_uptime_timeout = config.align_uptime_timeout.as_secs_f64();
+
+    // Wait for a free slot if too many traversals are in progress.
+    // Before connecting, so the remote doesn't start traversal while this side is queued
+    if state.traversals.available_permits() == 0 {
+        info!("Concurrent traversal limit is reached, waiting");
+    }
+    let traversal = select! {
+        permit = state.traversals.clone().acquire_owned() => permit.map_err(|_| ())?,
+        _ = state.cancellation.cancelled() => return Ok(()),
+    };
+    debug!(
+        "Traversals in progress: {}",
+        state.traversals_in_progress(&config)
+    );
+
     // Align connection time with session's uptime for firewall traversal effect
-    // Sleep until uptime value is dividable by `protocol::ALIGN_UPTIME_TIMEOUT`
+    // Sleep until uptime value is dividable by `align_uptime_timeout`
     let delay = match uptime {
-        Some(uptime) => protocol::ALIGN_UPTIME_TIMEOUT - (uptime % protocol::ALIGN_UPTIME_TIMEOUT),
+        // Alignment is disabled, peer is supposed to connect immediately as well
+        _ if !config.align_uptime => 0.0,
+        Some(uptime) => align_uptime_timeout - (uptime % align_uptime_timeout),
         // Uptime unknown. Prevent request flood
-        None => protocol::ALIGN_UPTIME_TIMEOUT,
+        None => align_uptime_timeout,
     };
 
     debug!("Delay: {delay:.2}s");
@@ -55,31 +168,77 @@ async fn connect_session(
         config.clone(),
         state.clone(),
         PeeringProtocol::Tcp,
-        config.listen_port,
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, config.listen_port)),
         address.into(),
         *address.ip(),
         None,
         None,
     )
     .await
-    .map_err(map_debug!("NAT traversal failed"))
-    {
+    .map_err(|err| {
+        // Remote is addressed by our own `listen_port`, mismatch looks the same as missing jumper
+        debug!("NAT traversal failed: {err}");
+        debug!(
+            "Help: Remote is assumed to run jumper on the same `listen_port` ({})",
+            address.port()
+        );
+    }) {
         let socket = match socket {
             RouterStream::Tcp(socket) => socket,
             _ => unreachable!(),
         };
-        return protocol::try_session(config, state, socket, address).await;
+        let result =
+            protocol::try_session(config.clone(), state.clone(), socket, address, traversal).await;
+        record_decision(
+            &config,
+            &mut *state.session_decisions.write().await,
+            *address.ip(),
+            match result {
+                Ok(()) => SessionDecision::SessionClosed,
+                Err(()) => SessionDecision::SessionFailed,
+            },
+        );
+        return result;
     }
+    record_decision(
+        &config,
+        &mut *state.session_decisions.write().await,
+        *address.ip(),
+        SessionDecision::TraversalFailed,
+    );
     Err(())
 }
 
-#[instrument(parent = None, name = "Session spawner", skip_all)]
-pub async fn spawn_new_sessions(
-    config: Config,
-    state: State,
-    external_required: watch::Sender<Instant>,
-) -> Result<(), ()> {
-    let whitelist_contains = config.whitelist.as_ref().map(|whitelist| {
+/// Reason to skip bridging the session, whichever side initiates it. Whitelist is checked by callers
+pub fn skip_reason(
+    config: &config::ConfigInner,
+    peers: &[PeerEntry],
+    address: Ipv6Addr,
+    uptime: Option<f64>,
+) -> Option<SessionDecision> {
+    // Skip if session is too young to be stable
+    if uptime.is_some_and(|uptime| Duration::from_secs_f64(uptime) < config.min_session_uptime) {
+        return Some(SessionDecision::TooYoung);
+    }
+
+    // Skip if peer already has direct connection, unless it's clearly slower
+    if config.avoid_redundant_peering
+        && peers.iter().any(|p| {
+            p.up && p.address.as_ref() == Some(&address)
+                && (config.redundant_peering_max_latency.is_zero()
+                    || p.latency
+                        .is_none_or(|latency| latency <= config.redundant_peering_max_latency))
+        })
+    {
+        return Some(SessionDecision::AlreadyPeered);
+    }
+
+    None
+}
+
+/// Build a matcher of addresses allowed by the `whitelist`, if it's set
+pub fn whitelist_filter(config: &config::ConfigInner) -> Option<impl Fn(&Ipv6Addr) -> bool> {
+    config.whitelist.as_ref().map(|whitelist| {
         const ADDRESS_PREFIX: u8 = 0x02;
         const SUBNET_PREFIX: u8 = 0x03;
         const SUBNET_BYTES: usize = 8;
@@ -105,13 +264,28 @@ pub async fn spawn_new_sessions(
         move |address: &Ipv6Addr| {
             addresses.contains(address) || subnets.contains(&get_subnet_id(address))
         }
-    });
+    })
+}
+
+/// Report the whitelist if it excludes every session for this long
+pub const WHITELIST_MISMATCH_WARNING_DELAY: Duration = Duration::from_secs(5 * 60);
+
+#[instrument(parent = None, name = "Session spawner", skip_all)]
+pub async fn spawn_new_sessions(
+    config: Config,
+    state: State,
+    external_required: watch::Sender<Instant>,
+) -> Result<(), ()> {
+    let whitelist_contains = whitelist_filter(&config);
 
     let cancellation = state.cancellation.clone();
     let watch_peers = state.watch_peers.clone();
     let mut watch_sessions = state.watch_sessions.clone();
     let mut watch_external = state.watch_external.clone();
 
+    // Time since the whitelist has been matching none of the sessions
+    let mut whitelist_mismatch_since: Option<Instant> = None;
+
     // Avoid warning on startup
     if watch_external.borrow().is_empty() {
         watch_external.changed().await.map_err(|_| ())?;
@@ -131,36 +305,61 @@ pub async fn spawn_new_sessions(
         {
             // For each connected session
             let mut reload_external = false;
+            let mut whitelist_matched = 0usize;
+            let mut whitelist_skipped = Vec::new();
             let mut sessions = state.active_sessions.write().await;
-            let peers = config.avoid_redundant_peering.then(|| watch_peers.borrow());
-            for session in watch_sessions.borrow_and_update().iter() {
+            let mut decisions = state.session_decisions.write().await;
+            let peers = watch_peers.borrow();
+            let watch_sessions = watch_sessions.borrow_and_update();
+
+            // Forget decisions about closed sessions
+            decisions.retain(|address, _| watch_sessions.iter().any(|s| &s.address == address));
+
+            for session in watch_sessions.iter() {
                 let address = session.address;
                 let uptime = session.uptime;
 
                 // Skip if address is not in the whitelist
                 if let Some(ref whitelist_contains) = whitelist_contains {
                     if !whitelist_contains(&address) {
+                        whitelist_skipped.push(address);
+                        record_decision(
+                            &config,
+                            &mut decisions,
+                            address,
+                            SessionDecision::NotWhitelisted,
+                        );
                         continue;
                     }
+                    whitelist_matched += 1;
                 }
 
-                // Skip if peer is already has direct connection
-                if let Some(ref peers) = peers {
-                    if peers.iter().any(|p| p.address.as_ref() == Some(&address)) {
-                        continue;
-                    }
+                // Skip if session isn't worth bridging
+                if let Some(decision) = skip_reason(&config, &peers, address, uptime) {
+                    record_decision(&config, &mut decisions, address, decision);
+                    continue;
+                }
+
+                if let Some(SessionType::Bridge) = sessions.get(&address) {
+                    record_decision(&config, &mut decisions, address, SessionDecision::Bridged);
                 }
 
                 // Spawn handler if session is new
                 if sessions.get(&address).is_none() {
                     // Refresh watchdog
-                    if reload_external == false {
+                    if !reload_external {
                         external_required.send(Instant::now()).ok();
                         reload_external = true;
                     }
 
                     // Add session record
                     sessions.insert(address, SessionType::Session);
+                    record_decision(
+                        &config,
+                        &mut decisions,
+                        address,
+                        SessionDecision::Connecting,
+                    );
 
                     // Spawn session handler
                     let config = config.clone();
@@ -183,6 +382,28 @@ pub async fn spawn_new_sessions(
                     });
                 }
             }
+
+            // Warn if the whitelist doesn't match any session for a long time
+            if whitelist_matched > 0 || whitelist_skipped.is_empty() {
+                whitelist_mismatch_since = None;
+            } else {
+                let since = whitelist_mismatch_since.get_or_insert_with(Instant::now);
+                if since.elapsed() > WHITELIST_MISMATCH_WARNING_DELAY {
+                    warn!(
+                        "Whitelist hasn't matched any of {} session(s) for {:?}, skipped: {}{}",
+                        whitelist_skipped.len(),
+                        since.elapsed(),
+                        whitelist_skipped.iter().take(3).join(", "),
+                        if whitelist_skipped.len() > 3 {
+                            ", ..."
+                        } else {
+                            ""
+                        },
+                    );
+                    warn!("Help: Check if `whitelist` in the config lists addresses of your peers");
+                    *since = Instant::now();
+                }
+            }
         }
 
         select! {
@@ -1,21 +1,78 @@
 use super::*;
 
 use {
-    bytecodec::{Decode, EncodeExt},
+    bytecodec::{Decode, DecodeExt, EncodeExt},
     rand::{rngs::StdRng, seq::SliceRandom, SeedableRng},
     stun_codec::{
         rfc5389::{attributes, methods::BINDING, Attribute},
+        rfc5780::attributes::{OtherAddress, ResponseOrigin},
         Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId,
     },
 };
 
-#[derive(Debug, PartialEq)]
+/// Attributes expected in binding responses, including ones of RFC 5780 (NAT Behavior Discovery)
+mod response {
+    use stun_codec::{
+        define_attribute_enums,
+        rfc5389::attributes::*,
+        rfc5780::attributes::{OtherAddress, ResponseOrigin},
+    };
+
+    define_attribute_enums!(
+        Attribute,
+        AttributeDecoder,
+        AttributeEncoder,
+        [
+            MappedAddress,
+            Username,
+            MessageIntegrity,
+            ErrorCode,
+            UnknownAttributes,
+            Realm,
+            Nonce,
+            XorMappedAddress,
+            XorMappedAddress2,
+            Software,
+            AlternateServer,
+            Fingerprint,
+            ResponseOrigin,
+            OtherAddress
+        ]
+    );
+}
+
+#[derive(Debug, Clone)]
 pub struct ExternalAddress {
     pub external: SocketAddr,
     pub local: SocketAddr,
     pub protocol: NetworkProtocol,
+    /// Last time the address was confirmed, see `external_address_ttl`
+    pub resolved_at: Instant,
+}
+
+/// Addresses are the same regardless of when they were resolved
+impl PartialEq for ExternalAddress {
+    fn eq(&self, other: &Self) -> bool {
+        (self.external, self.local, self.protocol) == (other.external, other.local, other.protocol)
+    }
+}
+
+/// Addresses reported by STUN server in binding response
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BindingResponse {
+    /// External address the request came from
+    pub mapped: SocketAddr,
+    /// Address the response was sent from, if reported (RFC 5780)
+    pub response_origin: Option<SocketAddr>,
+    /// Alternative server address for NAT behavior discovery, if reported (RFC 5780)
+    pub other_address: Option<SocketAddr>,
 }
 
+/// Window over which external address changes are counted
+pub const EXTERNAL_FLAP_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Number of external address changes within the window considered as flapping
+pub const EXTERNAL_FLAP_THRESHOLD: usize = 3;
+
 /// Monitor external internet addresses
 #[instrument(parent = None, name = "External address watcher ", skip_all)]
 pub async fn monitor(
@@ -35,29 +92,223 @@ pub async fn monitor(
         .unique()
         .collect();
 
+    for protocol in &protocols {
+        if !servers
+            .iter()
+            .any(|server| is_server_for(server, *protocol))
+        {
+            warn!("No STUN server is listed for {protocol:?} transport");
+        }
+    }
+
+    // Assign static candidates to listeners of the same family, preferably with the same port
+    let mut static_candidates = HashMap::<SocketAddr, Vec<SocketAddr>>::new();
+    for candidate in &config.static_candidates {
+        let same_family = || local.iter().filter(|l| l.is_ipv4() == candidate.is_ipv4());
+        let Some(local) = same_family()
+            .find(|l| l.port() == candidate.port())
+            .or_else(|| same_family().next())
+        else {
+            warn!("No listener matches family of static candidate {candidate}");
+            continue;
+        };
+        static_candidates
+            .entry(*local)
+            .or_default()
+            .push(*candidate);
+    }
+
+    // Times external addresses were lost recently
+    let mut flaps = std::collections::VecDeque::<Instant>::new();
+    let mut flap_warned_at = None::<Instant>;
+
+    // TCP connections to servers kept open between lookups
+    let mut kept = HashMap::<(SocketAddr, NetworkProtocol, String), TcpStream>::new();
+
+    // Sockets for which NAT has mapped different port, despite `assume_port_preserving`
+    let mut not_preserving = HashSet::<(SocketAddr, NetworkProtocol)>::new();
+
+    // Sockets for which servers have reported different external addresses
+    let mut inconsistent = HashSet::<(SocketAddr, NetworkProtocol)>::new();
+
     loop {
         let mut external = Vec::<ExternalAddress>::new();
+        let mut queried = HashSet::<(SocketAddr, NetworkProtocol, String)>::new();
 
         for local in &local {
+            // Skip STUN if external address is known in advance
+            if let Some(candidates) = static_candidates.get(local) {
+                for protocol in protocols.iter() {
+                    external.extend(candidates.iter().map(|candidate| ExternalAddress {
+                        external: *candidate,
+                        local: *local,
+                        protocol: *protocol,
+                        resolved_at: Instant::now(),
+                    }));
+                }
+                continue;
+            }
+
             for protocol in protocols.iter() {
                 if config.stun_randomize {
                     servers.shuffle(&mut random);
                 }
-                for server in &servers {
-                    let address = lookup(config.clone(), *protocol, *local, server).await;
-                    if let Ok(address) = address {
-                        external.push(address);
+                let mut resolved = None::<ExternalAddress>;
+                let mut suppressed = false;
+                let servers = servers
+                    .iter()
+                    .filter(|server| is_server_for(server, *protocol));
+                for server in servers {
+                    queried.insert((*local, *protocol, server.clone()));
+                    let Ok(address) = lookup_reusing(
+                        config.clone(),
+                        &state.dns_cache,
+                        *protocol,
+                        *local,
+                        server,
+                        Some(&mut kept),
+                    )
+                    .await
+                    else {
+                        continue;
+                    };
+                    let Some(ref first) = resolved else {
+                        resolved = Some(address);
+                        if config.stun_check_consistency {
+                            continue;
+                        }
                         break;
+                    };
+
+                    // Check address consistency
+                    let key = (*local, *protocol);
+                    if first.external == address.external {
+                        if inconsistent.remove(&key) {
+                            info!(
+                                "Servers report consistent address {} for {local} ({protocol:?})",
+                                address.external
+                            );
+                        }
+                    } else {
+                        if inconsistent.insert(key) {
+                            warn!(
+                                "Servers report different addresses for {local} ({protocol:?}): {}, {}",
+                                first.external, address.external,
+                            );
+                            warn!("NAT appears to be symmetric, peers are unlikely to traverse it");
+                        }
+                        if config.stun_suppress_inconsistent {
+                            resolved = None;
+                            suppressed = true;
+                        }
+                    }
+                    break;
+                }
+
+                // Advertise local port, but check if the NAT actually preserves it
+                if let Some(ref mut address) = resolved {
+                    let key = (*local, *protocol);
+                    if config.assume_port_preserving && address.external.port() != local.port() {
+                        if not_preserving.insert(key) {
+                            warn!(
+                                "NAT doesn't preserve port of {local} ({protocol:?}), mapped to {}",
+                                address.external
+                            );
+                            warn!("Help: Consider disabling `assume_port_preserving`");
+                        }
+                        address.external.set_port(local.port());
+                    } else {
+                        not_preserving.remove(&key);
+                    }
+                }
+
+                // Fallback. Assume the port is preserved by NAT, unless the NAT is known not to
+                if resolved.is_none() && !suppressed {
+                    for url in &config.external_ip_http {
+                        if let Ok(ip) =
+                            lookup_http(config.clone(), &state.dns_cache, *local, url).await
+                        {
+                            resolved = Some(ExternalAddress {
+                                external: SocketAddr::new(ip, local.port()),
+                                local: *local,
+                                protocol: *protocol,
+                                resolved_at: Instant::now(),
+                            });
+                            break;
+                        }
                     }
                 }
+                external.extend(resolved);
             }
         }
 
-        // Update watchers if externals changed
-        if watch_external.borrow().as_slice() != external.as_slice() {
+        // Drop addresses excluded by `candidates_allow` and `candidates_deny`
+        external.retain(|address| {
+            let matches = |ips: &Vec<IpAddr>| {
+                ips.contains(&address.local.ip()) || ips.contains(&address.external.ip())
+            };
+            let allowed = (config.candidates_allow.is_empty() || matches(&config.candidates_allow))
+                && !matches(&config.candidates_deny);
+            if !allowed {
+                debug!(
+                    "External address {} is not shared (local {}, {:?})",
+                    address.external, address.local, address.protocol
+                );
+            }
+            allowed
+        });
+
+        // Close connections to servers no longer queried
+        kept.retain(|key, _| queried.contains(key));
+
+        // Update watchers if externals changed, otherwise only refresh resolution times
+        if watch_external.borrow().as_slice() == external.as_slice() {
+            watch_external.send_if_modified(|old| {
+                *old = external;
+                false
+            });
+        } else {
+            {
+                let old = watch_external.borrow();
+                let mut lost = false;
+                for address in old.iter().filter(|a| !external.contains(a)) {
+                    info!(
+                        "External address {} is lost (local {}, {:?})",
+                        address.external, address.local, address.protocol
+                    );
+                    lost = true;
+                }
+
+                // Warn if external addresses change frequently
+                if lost {
+                    flaps.push_back(Instant::now());
+                    flaps.retain(|at| at.elapsed() < EXTERNAL_FLAP_WINDOW);
+                    if flaps.len() >= EXTERNAL_FLAP_THRESHOLD
+                        && flap_warned_at.is_none_or(|at| at.elapsed() > EXTERNAL_FLAP_WINDOW)
+                    {
+                        warn!(
+                            "External address changed {} times in {:?}",
+                            flaps.len(),
+                            EXTERNAL_FLAP_WINDOW
+                        );
+                        warn!("Help: NAT mapping appears to be unstable, sessions may fail intermittently");
+                        flap_warned_at = Some(Instant::now());
+                    }
+                }
+
+                for address in external.iter().filter(|a| !old.contains(a)) {
+                    info!(
+                        "External address {} is resolved (local {}, {:?})",
+                        address.external, address.local, address.protocol
+                    );
+                }
+            }
             watch_external.send(external).unwrap();
         }
 
+        // Local addresses used to reach internet, changed ones indicate network switch
+        let sources = route_sources(&config, &state.dns_cache, &local, &config.stun_servers).await;
+
         // Check is external address unresolved or update required
         let required = watch_external.borrow().is_empty()
             || external_required.borrow_and_update().elapsed()
@@ -67,16 +318,18 @@ pub async fn monitor(
             // Delay next request
             select! {
                 _ = sleep(config.resolve_external_address_delay) => {},
+                _ = network_changed(&config, &state.dns_cache, &local, &config.stun_servers, &sources) => {},
                 _ = cancellation.cancelled() => return Ok(()),
             };
         } else {
-            debug!("No update required, suspending");
+            info!("No update required, suspending external address resolution");
             loop {
                 // Wait until any new session is started
                 select! {
                     (err, ()) = async {
                         join!(external_required.changed(), sleep(config.resolve_external_address_delay))
                     } => err.map_err(|_| ())?,
+                    _ = network_changed(&config, &state.dns_cache, &local, &config.stun_servers, &sources) => break,
                     _ = cancellation.cancelled() => return Ok(()),
                 };
                 // Check if any bridge is running
@@ -90,33 +343,140 @@ pub async fn monitor(
                     break;
                 }
             }
+            info!("Resuming external address resolution");
+        }
+    }
+}
+
+/// Split optional transport scheme from the STUN server address.
+/// Servers without scheme are used with any transport
+pub fn parse_server(server: &str) -> Result<(Option<NetworkProtocol>, &str), ()> {
+    match server.split_once("://") {
+        None => Ok((None, server)),
+        Some(("tcp", address)) => Ok((Some(NetworkProtocol::Tcp), address)),
+        Some(("udp", address)) => Ok((Some(NetworkProtocol::Udp), address)),
+        Some((scheme, _)) => Err(error!(
+            "Unsupported STUN server scheme {scheme:?} in {server:?}, expected \"tcp\" or \"udp\""
+        )),
+    }
+}
+
+fn is_server_for(server: &str, protocol: NetworkProtocol) -> bool {
+    parse_server(server).is_ok_and(|(p, _)| p.is_none_or(|p| p == protocol))
+}
+
+/// Local address chosen by the OS to reach the first resolvable server, for each listener
+async fn route_sources(
+    config: &config::ConfigInner,
+    dns_cache: &utils::DnsCache,
+    local: &[SocketAddr],
+    servers: &[String],
+) -> Vec<Option<IpAddr>> {
+    let mut sources = Vec::new();
+    for local in local {
+        let mut source = None;
+        for server in servers {
+            let Ok((_, server)) = parse_server(server) else {
+                continue;
+            };
+            let Ok(server) = utils::resolve_in_domain(config, dns_cache, server, local).await
+            else {
+                continue;
+            };
+            source = utils::route_source(local.ip(), server);
+            break;
+        }
+        sources.push(source);
+    }
+    sources
+}
+
+/// Complete once route sources differ from `sources`, checking every `network_change_check_delay`
+async fn network_changed(
+    config: &config::ConfigInner,
+    dns_cache: &utils::DnsCache,
+    local: &[SocketAddr],
+    servers: &[String],
+    sources: &[Option<IpAddr>],
+) {
+    if config.network_change_check_delay.is_zero() {
+        return std::future::pending().await;
+    }
+    loop {
+        sleep(config.network_change_check_delay).await;
+        let current = route_sources(config, dns_cache, local, servers).await;
+        if current != sources {
+            info!("Network change detected, resolving external addresses anew");
+            return;
         }
     }
 }
 
 /// Lookup external internet address
-#[instrument(parent = None, name = "Lookup ", skip_all, fields(protocol = ?protocol, local = %local, server = %server))]
 pub async fn lookup(
     config: Config,
     protocol: NetworkProtocol,
     local: SocketAddr,
-    server: &String,
+    server: &str,
+) -> Result<ExternalAddress, ()> {
+    // Addresses aren't reused by a one-off lookup
+    let dns_cache = utils::DnsCache::default();
+    lookup_reusing(config, &dns_cache, protocol, local, server, None).await
+}
+
+/// Lookup external address, reusing TCP connections kept open by previous lookups
+#[instrument(parent = None, name = "Lookup ", skip_all, fields(protocol = ?protocol, local = %local, server = %server))]
+pub async fn lookup_reusing(
+    config: Config,
+    dns_cache: &utils::DnsCache,
+    protocol: NetworkProtocol,
+    local: SocketAddr,
+    server: &str,
+    mut kept: Option<&mut HashMap<(SocketAddr, NetworkProtocol, String), TcpStream>>,
 ) -> Result<ExternalAddress, ()> {
+    let key = (local, protocol, server.to_string());
+
+    // Check server transport
+    let server = match parse_server(server)? {
+        (Some(server_protocol), _) if server_protocol != protocol => {
+            return Err(info!("Server doesn't support {protocol:?} transport"));
+        }
+        (_, server) => server,
+    };
+
+    // Try connection kept from previous lookup, unless server has closed it
+    let kept_stream = kept
+        .as_mut()
+        .filter(|_| protocol == NetworkProtocol::Tcp)
+        .and_then(|kept| kept.remove(&key));
+    if let Some(stream) = kept_stream {
+        match stream.try_read(&mut [0u8; 1]) {
+            Err(err) if err.kind() == IoErrorKind::WouldBlock => {
+                let mut stream = RouterStream::Tcp(stream);
+                if let Ok(response) = lookup_external_address(config.clone(), &mut stream).await {
+                    debug!("Resolved over kept connection: {}", response.mapped);
+                    if let (Some(kept), RouterStream::Tcp(stream)) = (kept.as_mut(), stream) {
+                        kept.insert(key, stream);
+                    }
+                    return Ok(ExternalAddress {
+                        local,
+                        external: response.mapped,
+                        protocol,
+                        resolved_at: Instant::now(),
+                    });
+                }
+            }
+            _ => debug!("Kept connection is closed by server"),
+        }
+    }
+
     // Resolve server address
-    let server_address = lookup_host(server.as_str())
-        .await
-        .map_err(map_info!("Failed to lookup server address"))
-        .map(|addrs| {
-            addrs
-                .filter(|addr| addr.is_ipv4() == local.is_ipv4())
-                .next()
-                .ok_or_else(|| info!("No suitable address resolved"))
-        })??;
+    let server_address = utils::resolve_in_domain(&config, dns_cache, server, &local).await?;
 
     // Create server connection
     let mut stream = match protocol {
         NetworkProtocol::Tcp => {
-            let socket = utils::create_tcp_socket_in_domain(&local, local.port())?;
+            let socket = utils::create_tcp_socket(local, config.freebind)?;
             let socket = timeout(
                 config.stun_tcp_response_timeout,
                 socket.connect(server_address),
@@ -127,25 +487,31 @@ pub async fn lookup(
             socket.into()
         }
         NetworkProtocol::Udp => {
-            let socket = utils::create_udp_socket_in_domain(&local, local.port())?;
-            socket
-                .connect(server_address)
-                .await
-                .map_err(map_info!("Failed to connect to {server_address}"))?;
-            socket.into()
+            utils::create_connected_udp_socket(local, server_address, config.freebind)?.into()
         }
     };
 
     // Perform stun request
-    let external_address = stun::lookup_external_address(config.clone(), &mut stream).await?;
+    let external_address = stun::lookup_external_address(config.clone(), &mut stream)
+        .await?
+        .mapped;
 
-    // Unclean socket shutdown may cause an OS to temporarily disallow new reconnection
-    if let RouterStream::Tcp(ref mut stream) = stream {
-        stream
-            .shutdown()
-            .await
-            .map_err(map_info!("Failed to close connection"))
-            .ok();
+    // Keep connection for next lookup, or close it
+    if let RouterStream::Tcp(stream) = stream {
+        match kept {
+            Some(kept) if config.stun_tcp_reuse => {
+                kept.insert(key, stream);
+            }
+            // Unclean socket shutdown may cause an OS to temporarily disallow new reconnection
+            _ => {
+                let mut stream = stream;
+                stream
+                    .shutdown()
+                    .await
+                    .map_err(map_info!("Failed to close connection"))
+                    .ok();
+            }
+        }
     }
 
     debug!("Resolved: {}", external_address);
@@ -153,30 +519,98 @@ pub async fn lookup(
     Ok(ExternalAddress {
         local,
         external: external_address,
-        protocol: protocol,
+        protocol,
+        resolved_at: Instant::now(),
     })
 }
 
+const MAXIMUM_EXPECTED_HTTP_RESPONSE_SIZE: u64 = 4096;
+
+/// Lookup external IP address using plain HTTP service
+#[instrument(parent = None, name = "HTTP lookup ", skip_all, fields(local = %local, url = %url))]
+pub async fn lookup_http(
+    config: Config,
+    dns_cache: &utils::DnsCache,
+    local: SocketAddr,
+    url: &str,
+) -> Result<IpAddr, ()> {
+    let Some(location) = url.strip_prefix("http://") else {
+        return Err(warn!(
+            "Unsupported URL, expected 'http://<host>[:<port>][/<path>]'"
+        ));
+    };
+    let (host, path) = match location.find('/') {
+        Some(at) => location.split_at(at),
+        None => (location, "/"),
+    };
+    let address = match host.contains(':') && !host.ends_with(']') {
+        true => host.to_string(),
+        false => format!("{host}:80"),
+    };
+
+    // Resolve server address
+    let server_address =
+        utils::resolve_in_domain(&config, dns_cache, address.as_str(), &local).await?;
+
+    // Perform request from the local address, so the learned IP belongs to its interface
+    let socket = utils::create_tcp_socket(SocketAddr::new(local.ip(), 0), config.freebind)?;
+    let response = timeout(config.stun_tcp_response_timeout, async {
+        let mut stream = socket.connect(server_address).await?;
+        stream
+            .write_all(
+                format!("GET {path} HTTP/1.0\r\nHost: {host}\r\nConnection: close\r\n\r\n")
+                    .as_bytes(),
+            )
+            .await?;
+        let mut response = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(
+            &mut tokio::io::AsyncReadExt::take(stream, MAXIMUM_EXPECTED_HTTP_RESPONSE_SIZE),
+            &mut response,
+        )
+        .await?;
+        IoResult::Ok(response)
+    })
+    .await
+    .map_err(|_| info!("Failed to receive response: Timeout"))?
+    .map_err(map_info!("Failed to receive response"))?;
+
+    // Parse response
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| info!("Received incomplete response"))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(info!("Server responded with '{status}'"));
+    }
+    let ip = IpAddr::from_str(body.trim())
+        .map_err(|_| info!("Failed to parse address from response {:?}", body.trim()))?;
+    if ip.is_ipv4() != local.is_ipv4() {
+        return Err(info!("Resolved address has wrong range: {ip}"));
+    }
+
+    debug!("Resolved: {ip}");
+
+    Ok(ip)
+}
+
 const MAXIMUM_EXPECTED_STUN_PACKET_SIZE: usize = 1024;
 
 #[instrument(name = " STUN protocol", skip_all)]
 pub async fn lookup_external_address(
     config: Config,
     stream: &mut RouterStream,
-) -> Result<SocketAddr, ()> {
-    // Encode request
+) -> Result<BindingResponse, ()> {
+    // Encode request, random transaction id tells our response apart from stray ones
+    let transaction_id = TransactionId::new(rand::random());
     let request = MessageEncoder::<Attribute>::new()
-        .encode_into_bytes(Message::new(
-            MessageClass::Request,
-            BINDING,
-            TransactionId::new([0; 12]),
-        ))
+        .encode_into_bytes(Message::new(MessageClass::Request, BINDING, transaction_id))
         .expect("Failed to encode STUN request");
 
     // Send request and decode response
-    let mut decoder = MessageDecoder::<Attribute>::new();
-    match stream {
+    let decoded = match stream {
         RouterStream::Tcp(stream) => {
+            let mut decoder = MessageDecoder::<response::Attribute>::new();
             let mut stream = BufReader::with_capacity(MAXIMUM_EXPECTED_STUN_PACKET_SIZE, stream);
 
             stream
@@ -184,6 +618,7 @@ pub async fn lookup_external_address(
                 .await
                 .map_err(map_warn!("Failed to send request"))?;
 
+            let mut raw = Vec::new();
             let mut last_len = 0usize;
             loop {
                 let buf = timeout(config.stun_tcp_response_timeout, stream.fill_buf())
@@ -199,75 +634,409 @@ pub async fn lookup_external_address(
                     .decode(buf, bytecodec::Eos::new(false))
                     .map_err(map_warn!("Failed to decode server response"))?;
 
+                raw.extend_from_slice(&buf[..consumed]);
                 stream.consume(consumed);
 
                 if decoder.is_idle() {
                     break;
                 }
             }
+
+            decode_response(&config, &raw, decoder.finish_decoding())
+                .map_err(|err| warn!("Failed to decode server response: {err}"))?
         }
         RouterStream::Udp(stream) => {
             let mut is_timeout = true;
-            for _ in 0..config.stun_udp_retry_count {
+            let mut response = None;
+            'retry: for _ in 0..config.stun_udp_retry_count {
                 stream
                     .send(request.as_slice())
                     .await
                     .map_err(map_warn!("Failed to send request"))?;
 
                 let mut buf = [0u8; MAXIMUM_EXPECTED_STUN_PACKET_SIZE];
-                let mut consumed = 0usize;
                 loop {
-                    let written = timeout(
-                        config.stun_udp_response_timeout,
-                        stream.recv(&mut buf[consumed..]),
-                    )
-                    .await;
-                    let written = match written {
-                        Ok(written) => {
+                    let received =
+                        timeout(config.stun_udp_response_timeout, stream.recv(&mut buf)).await;
+                    let received = match received {
+                        Ok(received) => {
                             is_timeout = false;
-                            written.map_err(map_warn!("Failed to receive from socket"))?
+                            received.map_err(map_warn!("Failed to receive from socket"))?
                         }
-                        Err(_) => break,
+                        Err(_) => continue 'retry,
                     };
 
-                    let last_consumed = decoder
-                        .decode(&buf[..consumed + written], bytecodec::Eos::new(false))
-                        .map_err(map_warn!("Failed to decode server response"))?;
-
-                    buf.copy_within(last_consumed..consumed + written, 0);
-                    consumed = consumed + written - last_consumed;
+                    // Every datagram carries a whole message. Skip garbage, response may follow
+                    let message = match decode_response(
+                        &config,
+                        &buf[..received],
+                        MessageDecoder::<response::Attribute>::new()
+                            .decode_from_bytes(&buf[..received]),
+                    ) {
+                        Ok(message) => message,
+                        Err(err) => {
+                            debug!("Ignored undecodable datagram: {err}");
+                            continue;
+                        }
+                    };
 
-                    if decoder.is_idle() {
-                        break;
+                    // Skip responses to other requests, e.g. previous retries
+                    if message.0 != transaction_id {
+                        debug!("Ignored response with unexpected transaction id");
+                        continue;
                     }
+                    response = Some(message);
+                    break 'retry;
                 }
             }
-            if is_timeout {
-                info!("Failed to receive from socket: Timeout");
-                return Err(());
+            match response {
+                Some(response) => response,
+                None if is_timeout => {
+                    info!("Failed to receive from socket: Timeout");
+                    return Err(());
+                }
+                None => return Err(info!("Failed to receive expected response")),
             }
         }
     };
 
-    let decoded = decoder
-        .finish_decoding()
-        .map_err(map_warn!("Failed to decode server response"))?
-        .map_err(|err| warn!("Failed to decode server response {}", err.error()))?;
+    let (decoded_transaction_id, response) = decoded;
+    if decoded_transaction_id != transaction_id {
+        return Err(warn!("Server responded with unexpected transaction id"));
+    }
+    let response = response?;
+    let response_origin = response.response_origin;
 
-    let attrs = decoded;
-    if let Some(attr) = attrs.get_attribute::<attributes::XorMappedAddress>() {
-        return Ok(attr.address());
+    // Response sent from other address than requested may indicate redirecting or hijacked server
+    let server = match stream {
+        RouterStream::Tcp(stream) => stream.peer_addr(),
+        RouterStream::Udp(stream) => stream.peer_addr(),
+    };
+    if let (Some(origin), Ok(server)) = (response_origin, server) {
+        if origin != server {
+            warn!("Server {server} reported response origin {origin}, which differs from the address queried");
+        }
     }
-    if let Some(attr) = attrs.get_attribute::<attributes::XorMappedAddress2>() {
-        return Ok(attr.address());
+
+    Ok(response)
+}
+
+/// Extract transaction id and addresses from decoded binding response. Unless strict decoding is
+/// configured, malformed messages are still searched for mapped address, as some servers include
+/// non-standard attributes `stun_codec` fails to decode. Decoding error is left to callers to log
+fn decode_response(
+    config: &Config,
+    raw: &[u8],
+    decoded: bytecodec::Result<Result<Message<response::Attribute>, stun_codec::BrokenMessage>>,
+) -> Result<(TransactionId, Result<BindingResponse, ()>), String> {
+    let err = match decoded {
+        Ok(Ok(attrs)) => {
+            let mapped = attrs
+                .get_attribute::<attributes::XorMappedAddress>()
+                .map(|attr| attr.address())
+                .or_else(|| {
+                    attrs
+                        .get_attribute::<attributes::XorMappedAddress2>()
+                        .map(|attr| attr.address())
+                })
+                .or_else(|| {
+                    attrs
+                        .get_attribute::<attributes::MappedAddress>()
+                        .map(|attr| attr.address())
+                });
+            let response = match mapped {
+                Some(mapped) => Ok(BindingResponse {
+                    mapped,
+                    response_origin: attrs
+                        .get_attribute::<ResponseOrigin>()
+                        .map(|attr| attr.address()),
+                    other_address: attrs
+                        .get_attribute::<OtherAddress>()
+                        .map(|attr| attr.address()),
+                }),
+                None => Err(warn!(
+                    "Unable to find address attribute in server response: {:#?}",
+                    attrs
+                )),
+            };
+            return Ok((attrs.transaction_id(), response));
+        }
+        Ok(Err(broken)) => broken.error().to_string(),
+        Err(err) => err.to_string(),
+    };
+    if !config.stun_lenient_decoding {
+        return Err(err);
     }
-    if let Some(attr) = attrs.get_attribute::<attributes::MappedAddress>() {
-        return Ok(attr.address());
+    let Some((transaction_id, mapped)) = decode_lenient(raw) else {
+        return Err(err);
+    };
+    debug!("Server response decoded leniently, ignoring: {err}");
+    let response = mapped
+        .map(|mapped| BindingResponse {
+            mapped,
+            response_origin: None,
+            other_address: None,
+        })
+        .ok_or_else(|| warn!("Unable to find address attribute in server response"));
+    Ok((transaction_id, response))
+}
+
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+
+/// Walk raw message attributes, extracting only its transaction id and mapped address
+fn decode_lenient(raw: &[u8]) -> Option<(TransactionId, Option<SocketAddr>)> {
+    if raw.len() < 20 || raw[4..8] != STUN_MAGIC_COOKIE.to_be_bytes() {
+        return None;
     }
+    let transaction_id = TransactionId::new(raw[8..20].try_into().ok()?);
+    let (mut xor_mapped, mut mapped) = (None, None);
+    let mut attrs = &raw[20..];
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let length = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + length)?;
+        match kind {
+            // XOR-MAPPED-ADDRESS, including its pre-RFC 5389 type
+            0x0020 | 0x8020 => xor_mapped = xor_mapped.or(decode_address(value, &raw[4..20])),
+            // MAPPED-ADDRESS
+            0x0001 => mapped = mapped.or(decode_address(value, &[0; 16])),
+            _ => {}
+        }
+        // Values are padded to 4 bytes
+        attrs = attrs.get((4 + length + 3) & !3..).unwrap_or_default();
+    }
+    Some((transaction_id, xor_mapped.or(mapped)))
+}
+
+/// Decode address attribute value, `mask` is magic cookie followed by transaction id for XOR-ed ones
+fn decode_address(value: &[u8], mask: &[u8]) -> Option<SocketAddr> {
+    let ip = match (value.get(1)?, value.len()) {
+        (0x01, 8) => IpAddr::from(std::array::from_fn::<u8, 4, _>(|i| value[4 + i] ^ mask[i])),
+        (0x02, 20) => IpAddr::from(std::array::from_fn::<u8, 16, _>(|i| value[4 + i] ^ mask[i])),
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([value[2] ^ mask[0], value[3] ^ mask[1]]);
+    Some(SocketAddr::new(ip, port))
+}
 
-    warn!(
-        "Unable to find address attribute in server response: {:#?}",
-        attrs
+/// Comprehension-optional attribute type used to pad probe requests, servers are expected to ignore it
+const PROBE_PADDING_ATTRIBUTE: u16 = 0xFF00;
+
+/// Largest UDP payload size to probe, corresponds to 1500 bytes of link MTU over IPv4
+const PROBE_MAXIMUM_SIZE: usize = 1472;
+
+/// Estimate largest UDP payload reaching STUN server, by sending padded requests of varying size
+pub async fn probe_mtu(config: Config, local: SocketAddr, server: &str) -> Result<usize, ()> {
+    let server = match parse_server(server)? {
+        (Some(NetworkProtocol::Tcp), _) => {
+            return Err(error!(
+                "Server doesn't support {:?} transport",
+                NetworkProtocol::Udp
+            ));
+        }
+        (_, server) => server,
+    };
+    let server_address =
+        utils::resolve_in_domain(&config, &utils::DnsCache::default(), server, &local).await?;
+
+    let socket = utils::create_connected_udp_socket(local, server_address, config.freebind)?;
+    utils::set_dont_fragment(&socket)?;
+
+    // Send request of exactly `size` bytes, return whether it's answered
+    let probe = |size: usize| {
+        let socket = &socket;
+        let config = &config;
+        async move {
+            let transaction_id = TransactionId::new(rand::random());
+            let mut request = MessageEncoder::<Attribute>::new()
+                .encode_into_bytes(Message::new(MessageClass::Request, BINDING, transaction_id))
+                .expect("Failed to encode STUN request");
+            let padding = size.saturating_sub(request.len() + 4);
+            request.extend(PROBE_PADDING_ATTRIBUTE.to_be_bytes());
+            request.extend((padding as u16).to_be_bytes());
+            request.resize(request.len() + padding, 0);
+            let length = (request.len() - 20) as u16;
+            request[2..4].copy_from_slice(&length.to_be_bytes());
+
+            for _ in 0..config.stun_udp_retry_count {
+                // Fails if exceeds MTU of local interface
+                if let Err(err) = socket.send(&request).await {
+                    debug!("Failed to send {size} byte(s): {err}");
+                    return false;
+                }
+                let mut buf = [0u8; MAXIMUM_EXPECTED_STUN_PACKET_SIZE];
+                let deadline = Instant::now() + config.stun_udp_response_timeout;
+                while let Ok(Ok(received)) =
+                    tokio::time::timeout_at(deadline.into(), socket.recv(&mut buf)).await
+                {
+                    let response = MessageDecoder::<response::Attribute>::new()
+                        .decode_from_bytes(&buf[..received]);
+                    if let Ok(Ok(response)) = response {
+                        if response.transaction_id() == transaction_id {
+                            return true;
+                        }
+                    }
+                }
+            }
+            false
+        }
+    };
+
+    // Check the server is reachable at all, with only the padding header added
+    let mut good = 24;
+    if !probe(good).await {
+        return Err(error!("Server {server_address} doesn't respond"));
+    }
+
+    // Binary search over sizes aligned to 4 bytes, as required for attributes
+    let mut bad = PROBE_MAXIMUM_SIZE + 4;
+    while bad - good > 4 {
+        let size = (good + (bad - good) / 2) & !3;
+        let size = size.max(good + 4);
+        if probe(size).await {
+            debug!("{size} byte(s) passed");
+            good = size;
+        } else {
+            debug!("{size} byte(s) lost");
+            bad = size;
+        }
+    }
+    Ok(good)
+}
+
+/// Respond to STUN binding requests received by UDP socket
+#[instrument(parent = None, name = "STUN UDP server ", skip_all)]
+pub async fn serve_udp(socket: UdpSocket) -> Result<(), ()> {
+    // Accept padded requests as well, see `probe_mtu`
+    let mut buf = [0u8; PROBE_MAXIMUM_SIZE];
+    loop {
+        let (received, sender) = socket
+            .recv_from(&mut buf)
+            .await
+            .map_err(map_error!("Failed to receive from socket"))?;
+
+        let Ok(response) = binding_response(&buf[..received], sender) else {
+            continue;
+        };
+        socket
+            .send_to(response.as_slice(), sender)
+            .await
+            .map_err(map_info!("Failed to send response to {sender}"))
+            .ok();
+    }
+}
+
+const STUN_HEADER_SIZE: usize = 20;
+
+/// Close TCP connections of clients that send nothing for this long
+const SERVE_TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Respond to STUN binding requests received by TCP listener
+#[instrument(parent = None, name = "STUN TCP server ", skip_all)]
+pub async fn serve_tcp(listener: TcpListener) -> Result<(), ()> {
+    loop {
+        let (stream, sender) = listener
+            .accept()
+            .await
+            .map_err(map_error!("Failed to accept incoming connection"))?;
+
+        spawn(async move {
+            let mut stream = BufReader::with_capacity(MAXIMUM_EXPECTED_STUN_PACKET_SIZE, stream);
+            let mut buf = Vec::with_capacity(PROBE_MAXIMUM_SIZE);
+            loop {
+                // Answer every complete request buffered so far, requests may be pipelined
+                while buf.len() >= STUN_HEADER_SIZE {
+                    let len = STUN_HEADER_SIZE + u16::from_be_bytes([buf[2], buf[3]]) as usize;
+                    if len > PROBE_MAXIMUM_SIZE {
+                        return Err(debug!("Request from {sender} is too large"));
+                    }
+                    if buf.len() < len {
+                        break;
+                    }
+                    let response = binding_response(&buf[..len], sender)?;
+                    buf.drain(..len);
+                    stream
+                        .write_all(response.as_slice())
+                        .await
+                        .map_err(map_debug!("Failed to send response to {sender}"))?;
+                }
+
+                // Request may arrive in several segments
+                let received = match timeout(SERVE_TCP_IDLE_TIMEOUT, stream.fill_buf()).await {
+                    Err(_) => return Err(debug!("Connection from {sender} is idle, closing")),
+                    Ok(received) => received.map_err(map_debug!("Failed to read from {sender}"))?,
+                };
+                if received.is_empty() {
+                    return Result::<(), ()>::Ok(());
+                }
+                let received_len = received.len();
+                buf.extend_from_slice(received);
+                stream.consume(received_len);
+            }
+        });
+    }
+}
+
+/// Encode response to the STUN binding request
+fn binding_response(request: &[u8], sender: SocketAddr) -> Result<Vec<u8>, ()> {
+    let request = MessageDecoder::<Attribute>::new()
+        .decode_from_bytes(request)
+        .map_err(map_debug!("Failed to decode request from {sender}"))?
+        .map_err(|err| debug!("Failed to decode request from {sender}: {}", err.error()))?;
+
+    if request.class() != MessageClass::Request || request.method() != BINDING {
+        return Err(debug!("Unexpected request from {sender}"));
+    }
+
+    let mut response = Message::<Attribute>::new(
+        MessageClass::SuccessResponse,
+        BINDING,
+        request.transaction_id(),
     );
-    Err(())
+    response.add_attribute(attributes::XorMappedAddress::new(sender));
+
+    MessageEncoder::<Attribute>::new()
+        .encode_into_bytes(response)
+        .map_err(map_debug!("Failed to encode response to {sender}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lenient_decoding() {
+        let request = MessageEncoder::<Attribute>::new()
+            .encode_into_bytes(Message::new(
+                MessageClass::Request,
+                BINDING,
+                TransactionId::new([1; 12]),
+            ))
+            .unwrap();
+        for sender in ["192.0.2.1:4400", "[2001:db8::1]:4400"] {
+            let sender = SocketAddr::from_str(sender).unwrap();
+            let mut response = binding_response(&request, sender).unwrap();
+
+            // Append malformed ERROR-CODE attribute
+            response.extend([0x00, 0x09, 0x00, 0x01, 0xff, 0x00, 0x00, 0x00]);
+            let length = (response.len() - 20) as u16;
+            response[2..4].copy_from_slice(&length.to_be_bytes());
+
+            let decode = |config: Config| {
+                let decoded =
+                    MessageDecoder::<response::Attribute>::new().decode_from_bytes(&response);
+                decode_response(&config, &response, decoded)
+            };
+            let (transaction_id, decoded) =
+                decode(Arc::new(config::ConfigInner::default())).unwrap();
+            assert_eq!(transaction_id, TransactionId::new([1; 12]));
+            assert_eq!(decoded.unwrap().mapped, sender);
+
+            let strict = config::ConfigInner {
+                stun_lenient_decoding: false,
+                ..Default::default()
+            };
+            assert!(decode(Arc::new(strict)).is_err());
+        }
+    }
 }
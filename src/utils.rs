@@ -2,10 +2,13 @@ use super::*;
 
 mod cancellation;
 mod defer;
+mod logging;
 mod macros;
+mod resolver;
 mod sockets;
 
 pub use cancellation::*;
 pub use defer::*;
-pub use macros::*;
+pub use logging::*;
+pub use resolver::*;
 pub use sockets::*;
@@ -0,0 +1,103 @@
+use {
+    serde_json::{Map, Value},
+    std::fmt::{self, Write as _},
+    tracing::{field::Field, span::Record, Event, Subscriber},
+    tracing_subscriber::{
+        field::{RecordFields, Visit},
+        fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
+        registry::LookupSpan,
+    },
+};
+
+/// Collect recorded fields into a JSON object
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
+
+/// Format span fields as JSON object, so they can be merged into event by [`JsonFormat`]
+pub struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        fields.record(&mut visitor);
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    fn add_fields(&self, current: &mut FormattedFields<Self>, fields: &Record<'_>) -> fmt::Result {
+        let mut visitor = JsonVisitor(serde_json::from_str(&current.fields).unwrap_or_default());
+        fields.record(&mut visitor);
+        current.fields.clear();
+        write!(current.fields, "{}", Value::Object(visitor.0))
+    }
+}
+
+/// Print every event as a single line JSON object with fields of its spans
+pub struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut object = Map::new();
+        if let Ok(time) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            object.insert("timestamp".into(), time.as_secs_f64().into());
+        }
+        object.insert("level".into(), event.metadata().level().as_str().into());
+        object.insert("target".into(), event.metadata().target().into());
+
+        let mut visitor = JsonVisitor::default();
+        event.record(&mut visitor);
+        object.insert("fields".into(), Value::Object(visitor.0));
+
+        let mut spans = Vec::new();
+        for span in ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            let mut fields: Map<String, Value> = span
+                .extensions()
+                .get::<FormattedFields<N>>()
+                .and_then(|fields| serde_json::from_str(&fields.fields).ok())
+                .unwrap_or_default();
+            fields.insert("name".into(), span.name().trim().into());
+            spans.push(Value::Object(fields));
+        }
+        object.insert("spans".into(), Value::Array(spans));
+
+        writeln!(writer, "{}", Value::Object(object))
+    }
+}
@@ -0,0 +1,55 @@
+use {super::*, std::collections::BTreeMap, std::sync::Mutex};
+
+/// Recently resolved addresses by host
+#[derive(Default)]
+pub struct DnsCache(Mutex<BTreeMap<String, (Instant, Vec<SocketAddr>)>>);
+
+/// Resolve `host:port`, reusing addresses resolved within `dns_cache_ttl`.
+/// Falls back to previously resolved addresses if lookup fails
+pub async fn resolve(
+    config: &config::ConfigInner,
+    cache: &DnsCache,
+    host: &str,
+) -> Result<Vec<SocketAddr>, ()> {
+    let cached = cache.0.lock().unwrap().get(host).cloned();
+    if let Some((resolved_at, ref addresses)) = cached {
+        if resolved_at.elapsed() < config.dns_cache_ttl {
+            return Ok(addresses.clone());
+        }
+    }
+
+    match lookup_host(host).await {
+        Ok(addresses) => {
+            let addresses: Vec<SocketAddr> = addresses.collect();
+            if !config.dns_cache_ttl.is_zero() {
+                cache
+                    .0
+                    .lock()
+                    .unwrap()
+                    .insert(host.to_string(), (Instant::now(), addresses.clone()));
+            }
+            Ok(addresses)
+        }
+        Err(err) => match cached {
+            Some((_, addresses)) => {
+                debug!("Failed to lookup {host}, using previously resolved addresses: {err}");
+                Ok(addresses)
+            }
+            None => Err(info!("Failed to lookup {host}: {err}")),
+        },
+    }
+}
+
+/// Resolve `host:port` to an address of the same family as `domain`
+pub async fn resolve_in_domain(
+    config: &config::ConfigInner,
+    cache: &DnsCache,
+    host: &str,
+    domain: &SocketAddr,
+) -> Result<SocketAddr, ()> {
+    resolve(config, cache, host)
+        .await?
+        .into_iter()
+        .find(|address| address.is_ipv4() == domain.is_ipv4())
+        .ok_or_else(|| info!("No suitable address resolved for {host}"))
+}
@@ -4,12 +4,82 @@ pub trait RW: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
 impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> RW for T {}
 pub type RWSocket = Box<dyn RW>;
 
+/// Local address the OS would use to reach `remote`. Connecting UDP socket only selects route, nothing is sent
+pub fn route_source(local: IpAddr, remote: SocketAddr) -> Option<IpAddr> {
+    let socket = std::net::UdpSocket::bind(SocketAddr::new(local, 0)).ok()?;
+    socket.connect(remote).ok()?;
+    socket.local_addr().ok().map(|address| address.ip())
+}
+
+/// Addresses assigned to local network interfaces, except loopback and link-local ones
+#[cfg(target_os = "linux")]
+pub fn local_interface_addresses() -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: On success the list is owned by us until `freeifaddrs`
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        warn!(
+            "Failed to enumerate network interfaces: {}",
+            IoError::last_os_error()
+        );
+        return addresses;
+    }
+    let mut entry = list;
+    while !entry.is_null() {
+        // SAFETY: Entries and their addresses are valid as long as the list wasn't freed,
+        // `sockaddr` is cast only to the type denoted by its family
+        let ip = unsafe {
+            let address = (*entry).ifa_addr;
+            entry = (*entry).ifa_next;
+            match address.as_ref().map(|a| a.sa_family as libc::c_int) {
+                Some(libc::AF_INET) => {
+                    let address = &*(address as *const libc::sockaddr_in);
+                    IpAddr::from(u32::from_be(address.sin_addr.s_addr).to_be_bytes())
+                }
+                Some(libc::AF_INET6) => {
+                    let address = &*(address as *const libc::sockaddr_in6);
+                    IpAddr::from(address.sin6_addr.s6_addr)
+                }
+                _ => continue,
+            }
+        };
+        let link_local = match ip {
+            IpAddr::V4(ip) => ip.is_link_local(),
+            IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) == 0xfe80,
+        };
+        if !ip.is_loopback() && !link_local && !addresses.contains(&ip) {
+            addresses.push(ip);
+        }
+    }
+    // SAFETY: The list was allocated by `getifaddrs` and isn't used afterwards
+    unsafe { libc::freeifaddrs(list) };
+    addresses
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn local_interface_addresses() -> Vec<IpAddr> {
+    Vec::new()
+}
+
+/// Set `IP_FREEBIND` if enabled, so socket may be bound to address not assigned to the host
+#[allow(unused_variables)]
+fn apply_freebind(socket: &Socket, address: &SocketAddr, freebind: bool) -> IoResult<()> {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    if freebind {
+        match address {
+            SocketAddr::V4(_) => socket.set_freebind(true)?,
+            SocketAddr::V6(_) => socket.set_freebind_ipv6(true)?,
+        }
+    }
+    Ok(())
+}
+
 pub fn create_tcp_socket_ipv6(port: u16) -> Result<TcpSocket, ()> {
-    create_tcp_socket(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+    create_tcp_socket(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), false)
 }
 
 pub fn create_tcp_socket_ipv4(port: u16) -> Result<TcpSocket, ()> {
-    create_tcp_socket(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+    create_tcp_socket(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), false)
 }
 
 pub fn create_tcp_socket_in_domain(domain: &SocketAddr, port: u16) -> Result<TcpSocket, ()> {
@@ -20,7 +90,7 @@ pub fn create_tcp_socket_in_domain(domain: &SocketAddr, port: u16) -> Result<Tcp
 }
 
 #[instrument(name = "New socket ", skip_all, fields(address = %address))]
-pub fn create_tcp_socket(address: SocketAddr) -> Result<TcpSocket, ()> {
+pub fn create_tcp_socket(address: SocketAddr, freebind: bool) -> Result<TcpSocket, ()> {
     let map_err = map_error!("Failed to crate socket");
 
     let socket = Socket::new(
@@ -40,6 +110,7 @@ pub fn create_tcp_socket(address: SocketAddr) -> Result<TcpSocket, ()> {
     socket.set_reuse_address(true).map_err(map_err)?;
     #[cfg(unix)]
     socket.set_reuse_port(true).map_err(map_err)?;
+    apply_freebind(&socket, &address, freebind).map_err(map_err)?;
 
     socket
         .bind(&From::<SocketAddr>::from(address))
@@ -48,12 +119,57 @@ pub fn create_tcp_socket(address: SocketAddr) -> Result<TcpSocket, ()> {
     Ok(TcpSocket::from_std_stream(socket.into()))
 }
 
+/// Set "don't fragment" flag on outgoing packets, ignoring path MTU known to the OS
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(socket: &UdpSocket) -> Result<(), ()> {
+    use std::os::fd::AsRawFd;
+    let (level, name, value) = match socket
+        .local_addr()
+        .map_err(map_error!("Failed to get socket address"))?
+    {
+        SocketAddr::V4(_) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        ),
+        SocketAddr::V6(_) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        ),
+    };
+    // SAFETY: The option value is a valid c_int living through the call
+    let err = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if err != 0 {
+        return Err(error!(
+            "Failed to set \"don't fragment\" flag: {}",
+            IoError::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dont_fragment(_socket: &UdpSocket) -> Result<(), ()> {
+    Err(error!(
+        "Setting \"don't fragment\" flag isn't supported on this platform"
+    ))
+}
+
 pub fn create_udp_socket_ipv6(port: u16) -> Result<UdpSocket, ()> {
-    create_udp_socket(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+    create_udp_socket(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), false)
 }
 
 pub fn create_udp_socket_ipv4(port: u16) -> Result<UdpSocket, ()> {
-    create_udp_socket(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+    create_udp_socket(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), false)
 }
 
 pub fn create_udp_socket_in_domain(domain: &SocketAddr, port: u16) -> Result<UdpSocket, ()> {
@@ -63,7 +179,49 @@ pub fn create_udp_socket_in_domain(domain: &SocketAddr, port: u16) -> Result<Udp
     }
 }
 
-pub fn create_udp_socket(address: SocketAddr) -> Result<UdpSocket, ()> {
+/// Create socket on an ephemeral port. With `SO_REUSEPORT` the OS hands datagrams to any
+/// unconnected socket sharing the port, so sockets on a given port are created by
+/// `create_connected_udp_socket` instead
+pub fn create_udp_socket(address: SocketAddr, freebind: bool) -> Result<UdpSocket, ()> {
+    if address.port() != 0 {
+        return Err(error!(
+            "Refused to create unconnected socket on port {}, it would steal the port traffic",
+            address.port()
+        ));
+    }
+    let socket = bind_udp_socket(address, freebind)?;
+    UdpSocket::from_std(socket.into()).map_err(map_error!("Failed to crate socket"))
+}
+
+/// Create socket connected to `remote` before it's used, so it can share the local port
+/// without receiving datagrams meant for other sockets
+pub fn create_connected_udp_socket(
+    local: SocketAddr,
+    remote: SocketAddr,
+    freebind: bool,
+) -> Result<UdpSocket, ()> {
+    let socket = bind_udp_socket(local, freebind)?;
+    socket
+        .connect(&From::<SocketAddr>::from(remote))
+        .map_err(map_error!("Failed to connect to {remote}"))?;
+
+    // Discard datagrams from other sources queued before the socket was connected
+    let socket: std::net::UdpSocket = socket.into();
+    let mut buf = [0u8; 1];
+    while let Ok((_, source)) = socket.peek_from(&mut buf) {
+        if source == remote {
+            break;
+        }
+        trace!("Discarded datagram from {source} received before connecting");
+        if socket.recv(&mut buf).is_err() {
+            break;
+        }
+    }
+
+    UdpSocket::from_std(socket).map_err(map_error!("Failed to crate socket"))
+}
+
+fn bind_udp_socket(address: SocketAddr, freebind: bool) -> Result<Socket, ()> {
     let map_err = map_error!("Failed to crate socket");
 
     let socket = Socket::new(
@@ -83,10 +241,11 @@ pub fn create_udp_socket(address: SocketAddr) -> Result<UdpSocket, ()> {
     socket.set_reuse_address(true).map_err(map_err)?;
     #[cfg(unix)]
     socket.set_reuse_port(true).map_err(map_err)?;
+    apply_freebind(&socket, &address, freebind).map_err(map_err)?;
 
     socket
         .bind(&From::<SocketAddr>::from(address))
         .map_err(map_err)?;
 
-    Ok(UdpSocket::from_std(socket.into()).map_err(map_err)?)
+    Ok(socket)
 }
@@ -0,0 +1,338 @@
+//! Run two jumpers against each other over the loopback interface
+
+use yggdrasil_jumper::*;
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+struct Jumper {
+    config: Config,
+    state: State,
+    router: TcpListener,
+    _watch_external: watch::Sender<Vec<ExternalAddress>>,
+    _watch_sessions: watch::Sender<Vec<SessionEntry>>,
+    _watch_peers: watch::Sender<Vec<PeerEntry>>,
+    _admin_api: tokio::io::DuplexStream,
+}
+
+async fn stun_server() -> String {
+    let socket = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+    let address = socket.local_addr().unwrap();
+    let listener = TcpListener::bind(address).await.unwrap();
+
+    spawn(stun::serve_udp(socket));
+    spawn(stun::serve_tcp(listener));
+
+    address.to_string()
+}
+
+async fn jumper(address: Ipv6Addr, stun_server: &str, cancellation: &CancellationUnit) -> Jumper {
+    // Router listen socket bridge will connect to
+    let router = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+
+    let config = Arc::new(config::ConfigInner {
+        allow_ipv4: true,
+        allow_ipv6: false,
+        listen_port: 0,
+        yggdrasil_listen: vec![format!("tcp://{}", router.local_addr().unwrap())],
+        yggdrasil_protocols: vec![PeeringProtocol::Tcp],
+        stun_servers: vec![stun_server.to_string()],
+        ..Default::default()
+    });
+
+    let (admin_api, _admin_api) = tokio::io::duplex(1024);
+    let watch_sessions = watch::channel(Vec::new());
+    let watch_peers = watch::channel(Vec::new());
+    let watch_external = watch::channel(Vec::new());
+
+    let state = State::new(StateInner {
+        router: RwLock::new(RouterState {
+            version: [0, 5, 0],
+            address,
+            uri: "tcp://localhost:9001".to_string(),
+            admin_api: Endpoint::attach_version(
+                Box::new(admin_api),
+                yggdrasilctl::RouterVersion::v0_5_0__,
+            ),
+            address_changed: CancellationToken::new(),
+        }),
+        watch_external: watch_external.1,
+        watch_sessions: watch_sessions.1,
+        watch_peers: watch_peers.1,
+        active_sessions: RwLock::new(HashMap::new()),
+        active_sockets_tcp: RwLock::new(HashMap::new()),
+        session_decisions: RwLock::new(HashMap::new()),
+        traversals: StateInner::traversal_limit(&config),
+        registered_peers: Default::default(),
+        dns_cache: Default::default(),
+        cancellation: cancellation.clone(),
+    });
+
+    let (listeners, locals) =
+        network::create_listener_sockets(config.clone(), state.clone()).unwrap();
+    spawn(network::setup_listeners(
+        config.clone(),
+        state.clone(),
+        listeners,
+    ));
+
+    // Resolve external addresses
+    let mut external = Vec::new();
+    for local in locals {
+        let address = stun::lookup(config.clone(), NetworkProtocol::Tcp, local, stun_server)
+            .await
+            .unwrap();
+        assert_eq!(address.external, SocketAddr::new(LOCALHOST, local.port()));
+        external.push(address);
+    }
+    watch_external.0.send(external).unwrap();
+
+    Jumper {
+        config,
+        state,
+        router,
+        _watch_external: watch_external.0,
+        _watch_sessions: watch_sessions.0,
+        _watch_peers: watch_peers.0,
+        _admin_api,
+    }
+}
+
+#[tokio::test]
+async fn stun_lookup() {
+    let server = stun_server().await;
+    let config = Arc::new(config::ConfigInner::default());
+
+    for protocol in [NetworkProtocol::Tcp, NetworkProtocol::Udp] {
+        let local = utils::create_udp_socket(SocketAddr::new(LOCALHOST, 0), false)
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let address = stun::lookup(config.clone(), protocol, local, &server)
+            .await
+            .unwrap();
+        assert_eq!(address.external, local);
+        assert_eq!(address.protocol, protocol);
+    }
+
+    // TCP connection is kept and reused for the next lookup
+    let local = utils::create_tcp_socket(SocketAddr::new(LOCALHOST, 0), false)
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut kept = HashMap::new();
+    let dns_cache = utils::DnsCache::default();
+    for _ in 0..2 {
+        let address = stun::lookup_reusing(
+            config.clone(),
+            &dns_cache,
+            NetworkProtocol::Tcp,
+            local,
+            &server,
+            Some(&mut kept),
+        )
+        .await
+        .unwrap();
+        assert_eq!(address.external, local);
+        assert_eq!(kept.len(), 1);
+    }
+
+    // UDP lookup doesn't take over the kept TCP connection
+    let address = stun::lookup_reusing(
+        config.clone(),
+        &dns_cache,
+        NetworkProtocol::Udp,
+        local,
+        &server,
+        Some(&mut kept),
+    )
+    .await
+    .unwrap();
+    assert_eq!(address.protocol, NetworkProtocol::Udp);
+    assert_eq!(kept.len(), 1);
+
+    // Servers with transport scheme are used only for that transport
+    let local = SocketAddr::new(LOCALHOST, 0);
+    let server = format!("tcp://{server}");
+    assert!(
+        stun::lookup(config.clone(), NetworkProtocol::Tcp, local, &server)
+            .await
+            .is_ok()
+    );
+    assert!(
+        stun::lookup(config.clone(), NetworkProtocol::Udp, local, &server)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn stun_tcp_pipelined() {
+    use tokio::io::AsyncReadExt;
+
+    let server = stun_server().await;
+    let mut stream = TcpStream::connect(server.as_str()).await.unwrap();
+
+    // Binding requests with distinct transaction ids
+    let request = |id: u8| {
+        let mut request = vec![0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42];
+        request.extend([id; 12]);
+        request
+    };
+    let requests = [request(1), request(2)].concat();
+
+    // Split both requests across segments
+    for chunk in [&requests[..7], &requests[7..30], &requests[30..]] {
+        stream.write_all(chunk).await.unwrap();
+        stream.flush().await.unwrap();
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    for id in [1, 2] {
+        let mut header = [0u8; 20];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[..2], [0x01, 0x01]);
+        assert_eq!(header[8..], [id; 12]);
+        let mut body = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+        stream.read_exact(&mut body).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn path_probe() {
+    let first = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+    let second = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+    first.connect(second.local_addr().unwrap()).await.unwrap();
+    second.connect(first.local_addr().unwrap()).await.unwrap();
+
+    let (first, second) = join!(
+        network::probe_path(&first, 10),
+        network::probe_path(&second, 10)
+    );
+    for quality in [first.unwrap(), second.unwrap()] {
+        assert_eq!(quality.loss, 0.0);
+        assert!(quality.rtt.is_some());
+    }
+}
+
+#[tokio::test]
+async fn path_probe_unsynchronized() {
+    let first = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+    let second = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+    first.connect(second.local_addr().unwrap()).await.unwrap();
+    second.connect(first.local_addr().unwrap()).await.unwrap();
+
+    // Second side starts probing later, each side answers probes until the other is done
+    let (first, second) = join!(
+        async {
+            let quality = network::probe_path(&first, 10).await;
+            timeout(Duration::from_secs(1), network::answer_path_probes(&first))
+                .await
+                .ok();
+            quality
+        },
+        async {
+            timeout(
+                Duration::from_millis(500),
+                network::answer_path_probes(&second),
+            )
+            .await
+            .ok();
+            network::probe_path(&second, 10).await
+        }
+    );
+    for quality in [first.unwrap(), second.unwrap()] {
+        assert_eq!(quality.loss, 0.0);
+    }
+}
+
+#[tokio::test]
+async fn shared_udp_port() {
+    let first_remote = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+    let second_remote = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+
+    let first = utils::create_connected_udp_socket(
+        SocketAddr::new(LOCALHOST, 0),
+        first_remote.local_addr().unwrap(),
+        false,
+    )
+    .unwrap();
+    let local = first.local_addr().unwrap();
+    let second =
+        utils::create_connected_udp_socket(local, second_remote.local_addr().unwrap(), false)
+            .unwrap();
+
+    // Each socket receives only datagrams of its remote
+    second_remote.send_to(b"second", local).await.unwrap();
+    first_remote.send_to(b"first", local).await.unwrap();
+    let mut buf = [0u8; 16];
+    let received = first.recv(&mut buf).await.unwrap();
+    assert_eq!(&buf[..received], b"first");
+    let received = second.recv(&mut buf).await.unwrap();
+    assert_eq!(&buf[..received], b"second");
+
+    // Unconnected socket isn't allowed to share the port
+    assert!(utils::create_udp_socket(local, false).is_err());
+}
+
+#[tokio::test]
+async fn bridge() {
+    tracing_subscriber::fmt()
+        .with_max_level(Level::DEBUG)
+        .with_test_writer()
+        .init();
+    let (_cancellation_root, cancellation) = utils::cancellation();
+    let server = stun_server().await;
+
+    let first_address = Ipv6Addr::from_str("200::1").unwrap();
+    let second_address = Ipv6Addr::from_str("200::2").unwrap();
+    let first = jumper(first_address, &server, &cancellation).await;
+    let second = jumper(second_address, &server, &cancellation).await;
+
+    // Connection between jumpers over the yggdrasil network
+    let overlay = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+    let (first_socket, second_socket) = join!(
+        TcpStream::connect(overlay.local_addr().unwrap()),
+        overlay.accept()
+    );
+
+    spawn(protocol::try_session(
+        first.config.clone(),
+        first.state.clone(),
+        first_socket.unwrap(),
+        SocketAddrV6::new(second_address, 0, 0, 0),
+        first.state.traversals.clone().try_acquire_owned().unwrap(),
+    ));
+    spawn(protocol::try_session(
+        second.config.clone(),
+        second.state.clone(),
+        second_socket.unwrap().0,
+        SocketAddrV6::new(first_address, 0, 0, 0),
+        second.state.traversals.clone().try_acquire_owned().unwrap(),
+    ));
+
+    // Both bridges connect to the routers
+    let timeout = Duration::from_secs(10);
+    let (first_router, second_router) = join!(
+        tokio::time::timeout(timeout, first.router.accept()),
+        tokio::time::timeout(timeout, second.router.accept())
+    );
+    let (mut first_router, _) = first_router.unwrap().unwrap();
+    let (mut second_router, _) = second_router.unwrap().unwrap();
+
+    // Traffic is relayed between routers
+    first_router.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    tokio::io::AsyncReadExt::read_exact(&mut second_router, &mut buf)
+        .await
+        .unwrap();
+    assert_eq!(&buf, b"ping");
+
+    second_router.write_all(b"pong").await.unwrap();
+    tokio::io::AsyncReadExt::read_exact(&mut first_router, &mut buf)
+        .await
+        .unwrap();
+    assert_eq!(&buf, b"pong");
+
+    assert!(first.state.active_sessions.read().await[&second_address].is_bridge());
+    assert!(second.state.active_sessions.read().await[&first_address].is_bridge());
+}